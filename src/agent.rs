@@ -3,11 +3,18 @@ use std::collections::HashSet;
 use bevy::{gizmos::config::DefaultGizmoConfigGroup, prelude::*, sprite::Anchor};
 
 use crate::{
-    animation::{AnimationDirection, AnimationTimer, CharacterAnimations, CharacterSpriteSheet},
+    animation::{
+        AnimationController, AnimationDirection, AnimationTimer, CHARACTER_ANIMATION_SET,
+        CharacterSpriteSheet,
+    },
+    appearance::Appearance,
+    clock::DayNightTint,
     constants::*,
+    depth::YSort,
     events::{AgentEnteredTile, AgentLeftTile},
     pathfinder::Pathfinder,
-    world::{components::*, grid::*, spatial_idx::*},
+    visibility::VisibleTiles,
+    world::{agent_idx::AgentIndex, components::*, grid::*, rooms::CurrentRoom, spatial_idx::*},
 };
 
 #[derive(Component)]
@@ -18,19 +25,21 @@ pub struct AgentPlugin;
 impl Plugin for AgentPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(SpawnAgentTimer(Timer::from_seconds(2.0, TimerMode::Once)))
+            .init_resource::<SelectedAgent>()
             .add_observer(update_pathfinding_curr_step)
             .add_observer(pathfinding_finish_path_step)
             .add_observer(update_agent_position)
+            .add_observer(on_stop_walking)
             .add_systems(
                 Update,
                 (
-                    define_destination_system,
                     check_reach_destination_system,
                     movement_agent,
                     check_agent_pathfinding,
                     spawn_agent_system,
                     toggle_pathfinding_ui_visibility,
                     update_agent_colors_based_on_gizmos,
+                    select_agent_on_click,
                 ),
             );
     }
@@ -39,6 +48,16 @@ impl Plugin for AgentPlugin {
 #[derive(Resource)]
 struct SpawnAgentTimer(Timer);
 
+/// Agent picked with the mouse, used by debug overlays
+#[derive(Resource, Default)]
+pub struct SelectedAgent(pub Option<Entity>);
+
+/// The selected agent was clicked again
+#[derive(Event, Debug)]
+pub struct SelectedAgentClicked {
+    pub entity: Entity,
+}
+
 /// Marker for the agent
 #[derive(Component)]
 pub struct Agent {
@@ -97,16 +116,23 @@ fn spawn_agent_system(
     timer: Option<ResMut<SpawnAgentTimer>>,
     query: Query<&GridPosition, (With<Tile>, Without<Occupied>)>,
     spatial_idx: Res<SpatialIndex>,
-    character_sprite_sheet: Res<CharacterSpriteSheet>,
-    animations: Res<CharacterAnimations>,
+    character_sprite_sheet: Option<Res<CharacterSpriteSheet>>,
 ) {
+    // Wait for the character animation set to be loaded
+    let Some(character_sprite_sheet) = character_sprite_sheet else {
+        return;
+    };
+
     if let Some(mut timer) = timer {
         if timer.0.tick(time.delta()).just_finished() {
             for _ in 0..AGENTS_COUNT {
                 let mut done = false;
                 while !done {
-                    let grid_pos = Grid::get_random_position();
-                    if let Some(tile_data) = spatial_idx.map.get(&(grid_pos.x, grid_pos.y)) {
+                    // Levels are not loaded yet, try again next frame
+                    let Some(grid_pos) = Grid::get_random_position(&spatial_idx) else {
+                        return;
+                    };
+                    if let Some(tile_data) = spatial_idx.get(grid_pos.x, grid_pos.y) {
                         if tile_data.is_outside() {
                             if let Ok(_) = query.get(tile_data.entity) {
                                 done = true;
@@ -137,7 +163,7 @@ fn spawn_agent_system(
                                             layout: character_sprite_sheet
                                                 .texture_atlas_layout
                                                 .clone(),
-                                            index: animations.walk_down.first,
+                                            index: 0,
                                         },
                                     ),
                                     Anchor::BOTTOM_CENTER,
@@ -147,9 +173,14 @@ fn spawn_agent_system(
                                         z: AGENT_Z_VALUE,
                                     })
                                     .with_scale(Vec3::splat(0.8)),
-                                    animations.walk_down,
+                                    AnimationController::new(CHARACTER_ANIMATION_SET, "idle"),
                                     AnimationDirection::Down,
                                     AnimationTimer(Timer::from_seconds(0.1, TimerMode::Repeating)),
+                                    VisibleTiles::default(),
+                                    YSort {
+                                        base: AGENT_Z_VALUE,
+                                    },
+                                    CurrentRoom::default(),
                                 ));
                             }
                         }
@@ -161,32 +192,6 @@ fn spawn_agent_system(
     }
 }
 
-fn define_destination_system(
-    mut query: Query<Entity, (Without<Walking>, With<Agent>)>,
-    tile_query: Query<&Tile, Without<Occupied>>,
-    spatial_idx: Res<SpatialIndex>,
-    mut commands: Commands,
-) {
-    for agent_entity in &mut query {
-        let mut chosen_destination_pos: Option<GridPosition> = None;
-        while chosen_destination_pos.is_none() {
-            let pos = Grid::get_random_position();
-            if let Some(tile_data) = spatial_idx.map.get(&(pos.x, pos.y)) {
-                if tile_data.is_valid_destination() {
-                    if let Ok(_) = tile_query.get(tile_data.entity) {
-                        chosen_destination_pos = Some(pos);
-                    }
-                }
-            }
-        }
-        if let Some(destination_pos) = chosen_destination_pos {
-            commands.entity(agent_entity).insert(Walking {
-                destination: destination_pos,
-            });
-        }
-    }
-}
-
 fn check_reach_destination_system(
     query: Query<(Entity, &GridPosition, &Walking), With<Agent>>,
     mut commands: Commands,
@@ -198,6 +203,12 @@ fn check_reach_destination_system(
     }
 }
 
+fn on_stop_walking(remove: On<Remove, Walking>, mut query: Query<&mut AnimationController>) {
+    if let Ok(mut controller) = query.get_mut(remove.entity) {
+        controller.play("idle");
+    }
+}
+
 #[derive(Default)]
 struct OccupiedNow {
     pos: Vec<Entity>,
@@ -267,9 +278,17 @@ fn check_agent_pathfinding(
 
                         let next_position = current_path.path.get(*step).expect("Out of bounds");
 
-                        let tile_entity = spatial_idx
-                            .get_entity(next_position.x, next_position.y)
-                            .expect("next position do not exist");
+                        // The level holding the next step was unloaded
+                        let Some(tile_entity) =
+                            spatial_idx.get_entity(next_position.x, next_position.y)
+                        else {
+                            pathfinding
+                                .start_path_calculation(agent_curr_position, &walking.destination);
+
+                            UpdateAgentColor::calculating_path(&mut commands, agent_entity);
+
+                            continue;
+                        };
 
                         if occupied_now.pos.contains(&tile_entity) {
                             *retry += 1;
@@ -390,10 +409,10 @@ fn movement_agent(
         (
             Entity,
             &GridPosition,
+            &Walking,
             &mut Transform,
             &mut AnimationDirection,
-            &mut AnimationTimer,
-            &mut Sprite,
+            &mut AnimationController,
             &Agent,
         ),
         With<Walking>,
@@ -401,24 +420,41 @@ fn movement_agent(
     p_query: Query<&GridPosition, With<AgentPathfinding>>,
     time: Res<Time>,
     mut commands: Commands,
-    animations: Res<CharacterAnimations>,
 ) {
-    for (entity, agent_position, mut transform, mut anim_direction, mut timer, mut sprite, agent) in
-        query
+    for (
+        entity,
+        agent_position,
+        walking,
+        mut transform,
+        mut anim_direction,
+        mut controller,
+        agent,
+    ) in query
     {
         if let Ok(pathfinding_position) = p_query.get(agent.pathfinding_entity) {
             if pathfinding_position.ne(agent_position) {
-                timer.unpause();
+                // Far away destinations are worth running to
+                let remaining = (walking.destination.x - agent_position.x)
+                    .abs()
+                    .max((walking.destination.y - agent_position.y).abs());
+
+                let speed = if remaining > RUN_DISTANCE {
+                    controller.play("run");
+                    RUN_SPEED
+                } else {
+                    controller.play("walk");
+                    WALK_SPEED
+                };
 
                 let current_point = transform.translation;
                 let mut target_point =
                     Grid::grid_to_world(pathfinding_position.x, pathfinding_position.y);
 
-                target_point.z = AGENT_Z_VALUE;
+                // z is owned by the y-sort
+                target_point.z = current_point.z;
 
                 let to_target = target_point - current_point;
                 let distance = to_target.length();
-                let speed = 75.0;
 
                 let step = speed * time.delta_secs();
 
@@ -436,19 +472,8 @@ fn movement_agent(
                 } else {
                     let direction_vec = to_target.normalize();
 
-                    let new_direction = if direction_vec.x.abs() > direction_vec.y.abs() {
-                        if direction_vec.x > 0.0 {
-                            AnimationDirection::Right
-                        } else {
-                            AnimationDirection::Left
-                        }
-                    } else {
-                        if direction_vec.y > 0.0 {
-                            AnimationDirection::Up
-                        } else {
-                            AnimationDirection::Down
-                        }
-                    };
+                    let new_direction =
+                        AnimationDirection::from_vector(direction_vec.truncate(), *anim_direction);
 
                     if *anim_direction != new_direction {
                         *anim_direction = new_direction;
@@ -457,15 +482,7 @@ fn movement_agent(
                     transform.translation += direction_vec * step;
                 }
             } else {
-                if let Some(atlas) = &mut sprite.texture_atlas {
-                    timer.pause();
-                    match *anim_direction {
-                        AnimationDirection::Up => atlas.index = animations.walk_up.first,
-                        AnimationDirection::Down => atlas.index = animations.walk_down.first,
-                        AnimationDirection::Left => atlas.index = animations.walk_left.first,
-                        AnimationDirection::Right => atlas.index = animations.walk_right.first,
-                    }
-                }
+                controller.play("idle");
             }
         }
     }
@@ -487,14 +504,43 @@ fn toggle_pathfinding_ui_visibility(
 
 fn update_agent_colors_based_on_gizmos(
     config_store: Res<GizmoConfigStore>,
-    mut query: Query<(&mut Sprite, &AgentDebugColor), With<Agent>>,
+    mut query: Query<(&mut Sprite, &AgentDebugColor, Option<&Appearance>), With<Agent>>,
+    tint: Res<DayNightTint>,
 ) {
     let (config, _) = config_store.config::<DefaultGizmoConfigGroup>();
-    for (mut sprite, debug_color) in query.iter_mut() {
+    for (mut sprite, debug_color, appearance) in query.iter_mut() {
+        // Alpha belongs to the roof system (indoor agents)
+        let alpha = sprite.color.alpha();
         if config.enabled {
-            sprite.color = debug_color.0;
+            sprite.color = debug_color.0.with_alpha(alpha);
         } else {
-            sprite.color = Color::WHITE;
+            let body_color = appearance.map_or(Color::WHITE, |a| a.body.color());
+            sprite.color = tint.apply(body_color).with_alpha(alpha);
+        }
+    }
+}
+
+fn select_agent_on_click(
+    mouse: Res<ButtonInput<MouseButton>>,
+    cursor_tile: Res<CursorTile>,
+    agent_idx: Res<AgentIndex>,
+    mut selected: ResMut<SelectedAgent>,
+    mut commands: Commands,
+) {
+    if !mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+
+    if let Some(cursor_tile) = &cursor_tile.0 {
+        let clicked = agent_idx
+            .nearest_agent(cursor_tile, 1, None)
+            .map(|(entity, _)| entity);
+
+        if let Some(entity) = clicked
+            && selected.0 == clicked
+        {
+            commands.trigger(SelectedAgentClicked { entity });
         }
+        selected.0 = clicked;
     }
 }
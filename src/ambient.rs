@@ -0,0 +1,435 @@
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::{
+    agent::{Agent, Walking},
+    animation::{AnimationController, AnimationDirection},
+    background::{
+        ConversationId, DialogueCancelled, DialogueCompleted, DialogueFailed, DialogueQueued,
+        DialogueRequest, LlmService, RequestId,
+    },
+    clock::GameClock,
+    decision::{Brain, Engaged, Goal, TalkIntent},
+    dialogue::DialoguePanel,
+    facts::{RecentEvents, display_name},
+    message_animation::MessageCompleted,
+    persona::NpcPersona,
+    schedule::{Schedule, SchedulePaused},
+    world::{agent_idx::AgentIndex, components::GridPosition},
+};
+
+/// Real seconds between two looks for agents bumping into each other
+const ENCOUNTER_CHECK_SECONDS: f32 = 1.;
+/// Chance per check that an idle pair of neighbours strikes up a chat
+const ENCOUNTER_CHANCE: f32 = 0.2;
+
+/// Lines said in a chat, alternating between the two agents
+const CHAT_LINES: usize = 4;
+/// Game minutes before an agent chats again
+const CHAT_COOLDOWN_MINUTES: f64 = 180.;
+/// A chat whose line takes longer than this many real seconds is dropped
+const LINE_TIMEOUT_SECONDS: f32 = 60.;
+
+/// Global limits keeping the local model free for the player
+const MAX_CHATS: usize = 1;
+const MIN_SECONDS_BETWEEN_CHATS: f32 = 20.;
+
+pub struct AmbientChatPlugin;
+
+impl Plugin for AmbientChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AmbientChats>()
+            .add_observer(on_talk_intent)
+            .add_observer(on_dialogue_queued)
+            .add_observer(on_dialogue_completed)
+            .add_observer(on_message_completed)
+            .add_observer(on_dialogue_failed)
+            .add_observer(on_dialogue_cancelled)
+            .add_systems(Update, (detect_encounters, run_chats).chain());
+    }
+}
+
+/// Game minute an agent may chat again
+#[derive(Component)]
+pub struct ChatCooldown(pub f64);
+
+/// Two agents talking through the LLM, one line at a time
+struct Chat {
+    agents: [Entity; 2],
+    /// Index in `agents` of the one saying the next line
+    speaker: usize,
+    lines: Vec<(Entity, String)>,
+    /// The next line was asked for, its request ID comes with `DialogueQueued`
+    requested: bool,
+    pending: Option<RequestId>,
+    /// Real seconds spent on the requested line
+    waited: f32,
+    over: bool,
+}
+
+impl Chat {
+    fn listener(&self) -> Entity {
+        self.agents[1 - self.speaker]
+    }
+}
+
+#[derive(Resource)]
+pub struct AmbientChats {
+    chats: Vec<Chat>,
+    check_timer: Timer,
+    /// Real seconds since the last chat started
+    since_last: f32,
+}
+
+impl Default for AmbientChats {
+    fn default() -> Self {
+        Self {
+            chats: vec![],
+            check_timer: Timer::from_seconds(ENCOUNTER_CHECK_SECONDS, TimerMode::Repeating),
+            since_last: MIN_SECONDS_BETWEEN_CHATS,
+        }
+    }
+}
+
+impl AmbientChats {
+    fn is_chatting(&self, entity: Entity) -> bool {
+        self.chats.iter().any(|chat| chat.agents.contains(&entity))
+    }
+
+    fn has_room(&self, llm: &LlmService) -> bool {
+        self.chats.len() < MAX_CHATS
+            && self.since_last >= MIN_SECONDS_BETWEEN_CHATS
+            && llm.queued() == 0
+    }
+
+    fn chat_of(&mut self, request: RequestId) -> Option<&mut Chat> {
+        self.chats
+            .iter_mut()
+            .find(|chat| chat.pending == Some(request))
+    }
+}
+
+/// Goals an agent drops for a chat
+fn is_sociable(goal: Option<&Goal>) -> bool {
+    matches!(
+        goal,
+        None | Some(Goal::Wander | Goal::GoTo(_) | Goal::Wait { .. } | Goal::FollowSchedule)
+    )
+}
+
+type Candidate = (
+    Entity,
+    &'static GridPosition,
+    &'static Brain,
+    Option<&'static ChatCooldown>,
+);
+
+/// Neighbours with nothing better to do sometimes stop for a chat. Starts go
+/// through `TalkIntent`, like the conversations of the decision layer.
+fn detect_encounters(
+    mut chats: ResMut<AmbientChats>,
+    candidates_q: Query<Candidate, (With<Agent>, Without<Engaged>)>,
+    agent_idx: Res<AgentIndex>,
+    llm: Res<LlmService>,
+    clock: Res<GameClock>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    chats.since_last += time.delta_secs();
+    if !chats.check_timer.tick(time.delta()).just_finished() || !chats.has_room(&llm) {
+        return;
+    }
+
+    let now = clock.total_minutes();
+    let available = |entity: Entity| {
+        candidates_q
+            .get(entity)
+            .is_ok_and(|(_, _, brain, cooldown)| {
+                is_sociable(brain.goal.as_ref()) && cooldown.is_none_or(|until| now >= until.0)
+            })
+    };
+
+    let mut rng = rand::thread_rng();
+
+    for (entity, position, ..) in &candidates_q {
+        if !available(entity) || rng.r#gen::<f32>() >= ENCOUNTER_CHANCE {
+            continue;
+        }
+
+        // Diagonal neighbours count as adjacent too
+        let tile = IVec2::new(position.x, position.y);
+        let partner = agent_idx
+            .agents_in_rect(tile - IVec2::ONE, tile + IVec2::ONE)
+            .map(|(other, _)| other)
+            .find(|other| *other != entity && available(*other));
+
+        if let Some(partner) = partner {
+            commands.trigger(TalkIntent {
+                speaker: entity,
+                listener: partner,
+            });
+            return;
+        }
+    }
+}
+
+type Participant = (
+    &'static Transform,
+    &'static mut AnimationDirection,
+    Has<Schedule>,
+);
+
+/// Two agents starting to talk hold still, face each other and chat through
+/// the LLM when the limits allow it
+fn on_talk_intent(
+    talk: On<TalkIntent>,
+    mut chats: ResMut<AmbientChats>,
+    mut participants_q: Query<Participant, Without<Engaged>>,
+    llm: Res<LlmService>,
+    mut commands: Commands,
+) {
+    let agents = [talk.speaker, talk.listener];
+
+    if !chats.has_room(&llm) || agents.iter().any(|agent| chats.is_chatting(*agent)) {
+        return;
+    }
+
+    let Ok([mut speaker, mut listener]) = participants_q.get_many_mut(agents) else {
+        return;
+    };
+
+    let between = listener.0.translation.truncate() - speaker.0.translation.truncate();
+    *speaker.1 = AnimationDirection::from_vector(between, *speaker.1);
+    *listener.1 = AnimationDirection::from_vector(-between, *listener.1);
+
+    for (agent, (_, _, has_schedule)) in agents.into_iter().zip([&speaker, &listener]) {
+        let mut agent_commands = commands.entity(agent);
+        // Stopping plays "idle", so the clip comes after the removal
+        agent_commands
+            .insert(Engaged)
+            .remove::<Walking>()
+            .entry::<AnimationController>()
+            .and_modify(|mut controller| controller.play("talk"));
+        if *has_schedule {
+            agent_commands.insert(SchedulePaused);
+        }
+    }
+
+    debug!("{:?} and {:?} start chatting", talk.speaker, talk.listener);
+    chats.since_last = 0.;
+    chats.chats.push(Chat {
+        agents,
+        speaker: 0,
+        lines: vec![],
+        requested: false,
+        pending: None,
+        waited: 0.,
+        over: false,
+    });
+}
+
+fn on_dialogue_queued(queued: On<DialogueQueued>, mut chats: ResMut<AmbientChats>) {
+    let Some(chat) = chats
+        .chats
+        .iter_mut()
+        .find(|chat| chat.requested && chat.agents[chat.speaker] == queued.npc)
+    else {
+        return;
+    };
+
+    chat.requested = false;
+    chat.pending = Some(queued.request);
+}
+
+fn on_dialogue_completed(completed: On<DialogueCompleted>, mut chats: ResMut<AmbientChats>) {
+    let Some(chat) = chats.chat_of(completed.request) else {
+        return;
+    };
+
+    let line = completed.response.trim().to_string();
+    if line.is_empty() {
+        chat.over = true;
+        return;
+    }
+
+    chat.lines.push((completed.npc, line));
+}
+
+/// The next line waits for the bubble of the last one
+fn on_message_completed(completed: On<MessageCompleted>, mut chats: ResMut<AmbientChats>) {
+    let Some(chat) = chats.chat_of(completed.request) else {
+        return;
+    };
+
+    chat.pending = None;
+    chat.speaker = 1 - chat.speaker;
+    chat.over |= chat.lines.len() >= CHAT_LINES;
+}
+
+fn on_dialogue_failed(failed: On<DialogueFailed>, mut chats: ResMut<AmbientChats>) {
+    if let Some(chat) = chats.chat_of(failed.request) {
+        chat.over = true;
+    }
+}
+
+fn on_dialogue_cancelled(cancelled: On<DialogueCancelled>, mut chats: ResMut<AmbientChats>) {
+    if let Some(chat) = chats.chat_of(cancelled.request) {
+        chat.over = true;
+    }
+}
+
+/// Prompt of the next line, from the speaker's point of view
+fn line_prompt(chat: &Chat, listener: &str) -> String {
+    match chat.lines.last() {
+        None => format!(
+            "You run into {}. Greet them and say one short thing on your mind.",
+            listener
+        ),
+        Some((_, line)) => format!(
+            "{} says: \"{}\". Answer them in one short line.",
+            listener, line
+        ),
+    }
+}
+
+/// Lines of the chat from the point of view of `agent`
+fn transcript(chat: &Chat, agent: Entity, partner_name: &str) -> String {
+    let mut transcript = format!("You ran into {}.\n", partner_name);
+    for (speaker, line) in &chat.lines {
+        let name = if *speaker == agent {
+            "You"
+        } else {
+            partner_name
+        };
+        transcript.push_str(&format!("{}: {}\n", name, line));
+    }
+    transcript
+}
+
+type Chatter = (
+    &'static ConversationId,
+    Option<&'static NpcPersona>,
+    Option<&'static mut RecentEvents>,
+    &'static mut Brain,
+    Has<Schedule>,
+);
+
+/// Asks for the next line of each chat, and ends the finished ones
+fn run_chats(
+    mut chats: ResMut<AmbientChats>,
+    mut chatters_q: Query<Chatter>,
+    panel: Res<DialoguePanel>,
+    mut llm: ResMut<LlmService>,
+    clock: Res<GameClock>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let now = clock.total_minutes();
+
+    for chat in &mut chats.chats {
+        // The player takes over when talking to either of them
+        let interrupted = chat
+            .agents
+            .iter()
+            .any(|agent| panel.npc == Some(*agent) || !chatters_q.contains(*agent));
+
+        if chat.requested || chat.pending.is_some() {
+            chat.waited += time.delta_secs();
+            chat.over |= interrupted || chat.waited > LINE_TIMEOUT_SECONDS;
+
+            if chat.over
+                && let Some(request) = chat.pending.take()
+                && let Some(npc) = llm.cancel(request)
+            {
+                commands.trigger(DialogueCancelled { request, npc });
+            }
+            continue;
+        }
+
+        chat.over |= interrupted;
+        if chat.over {
+            continue;
+        }
+
+        let speaker = chat.agents[chat.speaker];
+        let (Ok((speaker_id, ..)), Ok((listener_id, listener_persona, ..))) =
+            (chatters_q.get(speaker), chatters_q.get(chat.listener()))
+        else {
+            chat.over = true;
+            continue;
+        };
+
+        // Each side keeps its own history of the chats with the other one
+        let conversation_id = format!("{}:{}", speaker_id.0, listener_id.0);
+        let prompt = line_prompt(chat, &display_name(listener_persona));
+
+        commands.trigger(DialogueRequest {
+            npc: speaker,
+            prompt,
+            conversation_id,
+        });
+        chat.requested = true;
+        chat.waited = 0.;
+    }
+
+    let (over, ongoing): (Vec<Chat>, Vec<Chat>) = chats.chats.drain(..).partition(|chat| chat.over);
+    chats.chats = ongoing;
+
+    for chat in over {
+        debug!(
+            "{:?} stop chatting after {} lines",
+            chat.agents,
+            chat.lines.len()
+        );
+
+        let (names, ids): (Vec<String>, Vec<Option<String>>) = chat
+            .agents
+            .iter()
+            .map(|agent| match chatters_q.get(*agent) {
+                Ok((id, persona, ..)) => (display_name(persona), Some(id.0.clone())),
+                Err(_) => (display_name(None), None),
+            })
+            .unzip();
+
+        for (idx, agent) in chat.agents.into_iter().enumerate() {
+            let partner = chat.agents[1 - idx];
+
+            // Each side keeps a summary of the chat with its own memories
+            if let (Some(id), Some(partner_id)) = (&ids[idx], &ids[1 - idx])
+                && !chat.lines.is_empty()
+            {
+                llm.remember_chat(
+                    format!("{}:{}", id, partner_id),
+                    transcript(&chat, agent, &names[1 - idx]),
+                );
+            }
+
+            let Ok((_, _, recent, mut brain, has_schedule)) = chatters_q.get_mut(agent) else {
+                continue;
+            };
+
+            if let Some(mut recent) = recent
+                && let Some((_, line)) = chat.lines.iter().rev().find(|(npc, _)| *npc == partner)
+            {
+                recent.remember(
+                    now,
+                    format!("You talked with {}, who said: \"{}\"", names[1 - idx], line),
+                );
+            }
+
+            let mut agent_commands = commands.entity(agent);
+            agent_commands.insert(ChatCooldown(now + CHAT_COOLDOWN_MINUTES));
+
+            // The panel keeps the agent the player is talking to
+            if panel.npc == Some(agent) {
+                continue;
+            }
+
+            brain.goal = None;
+            agent_commands.remove::<Engaged>();
+            if has_schedule {
+                agent_commands.remove::<SchedulePaused>();
+            }
+        }
+    }
+}
@@ -1,30 +1,315 @@
-use bevy::{math::UVec2, prelude::*};
+use std::{collections::HashMap, time::Duration};
 
-#[derive(Component, Clone, Copy)]
-pub struct Animation {
-    pub first: usize,
-    pub last: usize,
-}
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedFolder, io::Reader},
+    math::UVec2,
+    prelude::*,
+};
+use serde::Deserialize;
+
+use crate::named_folder::NamedFolder;
+
+/// Every `*.anim.json` file in this folder is an `AnimationSet`, named after the file
+const ANIMATION_SETS_DIR: &str = "animations";
+const ANIMATION_SET_EXTENSION: &str = "anim.json";
 
-#[derive(Component, Debug, PartialEq, Eq, Clone, Copy)]
+pub const CHARACTER_ANIMATION_SET: &str = "character";
+
+/// Extra degrees a movement must turn past the edge of the current facing
+/// sector before the facing changes, so near-45° movement doesn't flicker
+const FACING_HYSTERESIS_DEGREES: f32 = 10.;
+
+#[derive(Component, Debug, PartialEq, Eq, Clone, Copy, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AnimationDirection {
     Up,
     Down,
     Left,
     Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
 }
 
-#[derive(Component, Deref, DerefMut)]
-pub struct AnimationTimer(pub Timer);
+impl AnimationDirection {
+    /// Counter-clockwise from `Right`, 45° apart
+    const COMPASS: [AnimationDirection; 8] = [
+        AnimationDirection::Right,
+        AnimationDirection::UpRight,
+        AnimationDirection::Up,
+        AnimationDirection::UpLeft,
+        AnimationDirection::Left,
+        AnimationDirection::DownLeft,
+        AnimationDirection::Down,
+        AnimationDirection::DownRight,
+    ];
 
-#[derive(Resource)]
-pub struct CharacterAnimations {
-    pub walk_up: Animation,
-    pub walk_down: Animation,
-    pub walk_left: Animation,
-    pub walk_right: Animation,
+    fn angle(self) -> f32 {
+        let sector = Self::COMPASS
+            .iter()
+            .position(|direction| *direction == self)
+            .unwrap_or_default();
+        sector as f32 * 45.
+    }
+
+    /// Facing for a movement along `vector`, keeping `current` until the
+    /// movement is clearly in another sector
+    pub fn from_vector(vector: Vec2, current: AnimationDirection) -> AnimationDirection {
+        if vector == Vec2::ZERO {
+            return current;
+        }
+
+        let angle = vector.y.atan2(vector.x).to_degrees();
+
+        let from_current = (angle - current.angle() + 180.).rem_euclid(360.) - 180.;
+        if from_current.abs() <= 22.5 + FACING_HYSTERESIS_DEGREES {
+            return current;
+        }
+
+        let sector = (angle / 45.).round().rem_euclid(8.) as usize;
+        Self::COMPASS[sector]
+    }
+
+    /// Directions to look for in a sheet, best first. Diagonals fall back to
+    /// the side rows, which read better than up/down on 4-row sheets.
+    fn fallbacks(self) -> [AnimationDirection; 3] {
+        use AnimationDirection::*;
+
+        match self {
+            UpLeft => [UpLeft, Left, Up],
+            UpRight => [UpRight, Right, Up],
+            DownLeft => [DownLeft, Left, Down],
+            DownRight => [DownRight, Right, Down],
+            straight => [straight; 3],
+        }
+    }
+}
+
+/// Grid of equally sized frames in the sheet image
+#[derive(Deserialize, Clone, Debug)]
+pub struct SheetLayout {
+    /// Image path, relative to the assets folder
+    pub image: String,
+    pub frame_size: [u32; 2],
+    pub columns: u32,
+    pub rows: u32,
+    #[serde(default)]
+    pub padding: Option<[u32; 2]>,
+    #[serde(default)]
+    pub offset: Option<[u32; 2]>,
+}
+
+impl SheetLayout {
+    fn atlas_layout(&self) -> TextureAtlasLayout {
+        TextureAtlasLayout::from_grid(
+            UVec2::from(self.frame_size),
+            self.columns,
+            self.rows,
+            self.padding.map(UVec2::from),
+            self.offset.map(UVec2::from),
+        )
+    }
+}
+
+/// Inclusive range of columns in a direction row
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct FrameRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipMode {
+    #[default]
+    Loop,
+    /// Holds the last frame and fires `AnimationFinished`
+    Once,
+}
+
+/// Named event fired when a clip reaches `frame`, e.g. footsteps
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClipEvent {
+    pub frame: usize,
+    pub name: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AnimationClip {
+    pub frames: FrameRange,
+    /// Seconds per frame
+    pub frame_duration: f32,
+    /// Per-frame overrides of `frame_duration`
+    #[serde(default)]
+    pub frame_durations: Vec<f32>,
+    #[serde(default)]
+    pub mode: ClipMode,
+    /// Clip requested once a `Once` clip is over
+    #[serde(default)]
+    pub next: Option<String>,
+    #[serde(default)]
+    pub events: Vec<ClipEvent>,
+}
+
+impl AnimationClip {
+    fn frame_count(&self) -> usize {
+        self.frames.last - self.frames.first + 1
+    }
+
+    fn duration(&self, frame: usize) -> Duration {
+        Duration::from_secs_f32(
+            self.frame_durations
+                .get(frame)
+                .copied()
+                .unwrap_or(self.frame_duration),
+        )
+    }
+}
+
+/// Sheet layout, named clips of a character and the transitions allowed between them
+#[derive(Asset, TypePath, Deserialize, Clone, Debug)]
+pub struct AnimationSet {
+    pub sheet: SheetLayout,
+    /// Sheet row holding each direction. Missing diagonals use the side rows.
+    pub directions: HashMap<AnimationDirection, usize>,
+    pub default_clip: String,
+    pub clips: HashMap<String, AnimationClip>,
+    /// Clips reachable from a clip. Clips not listed can go to any clip.
+    #[serde(default)]
+    pub transitions: HashMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub texture: Handle<Image>,
+    #[serde(skip)]
+    pub texture_atlas_layout: Handle<TextureAtlasLayout>,
+}
+
+impl AnimationSet {
+    pub fn can_transition(&self, from: &str, to: &str) -> bool {
+        self.transitions
+            .get(from)
+            .is_none_or(|allowed| allowed.iter().any(|clip| clip == to))
+    }
+
+    fn atlas_index(&self, direction: AnimationDirection, column: usize) -> Option<usize> {
+        let row = direction
+            .fallbacks()
+            .iter()
+            .find_map(|direction| self.directions.get(direction))?;
+        Some(row * self.sheet.columns as usize + column)
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct AnimationSetLoader;
+
+impl AssetLoader for AnimationSetLoader {
+    type Asset = AnimationSet;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<AnimationSet, anyhow::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let mut set = serde_json::from_slice::<AnimationSet>(&bytes)?;
+
+        for (name, clip) in &set.clips {
+            if clip.frames.first > clip.frames.last
+                || clip.frames.last >= set.sheet.columns as usize
+            {
+                anyhow::bail!("clip {:?} has frames outside of the sheet", name);
+            }
+        }
+
+        set.texture = load_context.load(&set.sheet.image);
+        set.texture_atlas_layout =
+            load_context.add_labeled_asset("layout".to_string(), set.sheet.atlas_layout());
+
+        Ok(set)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[ANIMATION_SET_EXTENSION]
+    }
+}
+
+/// Animation sets found in `ANIMATION_SETS_DIR`, by name
+#[derive(Resource, Default)]
+pub struct AnimationLibrary {
+    folder: NamedFolder,
+    sets: HashMap<String, Handle<AnimationSet>>,
 }
 
+impl AnimationLibrary {
+    pub fn get(&self, name: &str) -> Option<&Handle<AnimationSet>> {
+        self.sets.get(name)
+    }
+}
+
+/// State machine playing the clips of an `AnimationSet` on the entity's sprite
+#[derive(Component, Debug)]
+pub struct AnimationController {
+    pub set: String,
+    clip: String,
+    frame: usize,
+    requested: Option<String>,
+    finished: bool,
+    restart: bool,
+}
+
+impl AnimationController {
+    pub fn new(set: &str, clip: &str) -> Self {
+        Self {
+            set: set.to_string(),
+            clip: clip.to_string(),
+            frame: 0,
+            requested: None,
+            finished: false,
+            restart: true,
+        }
+    }
+
+    /// Asks for `clip`. Applied once any running `Once` clip is over, going
+    /// through the default clip when the set forbids the direct transition.
+    pub fn play(&mut self, clip: &str) {
+        if self.clip != clip || self.requested.is_some() {
+            self.requested = Some(clip.to_string());
+        }
+    }
+
+    fn switch_to(&mut self, clip: String) {
+        self.clip = clip;
+        self.frame = 0;
+        self.finished = false;
+        self.restart = true;
+    }
+}
+
+/// Fired when a `Once` clip reaches its last frame
+#[derive(Event, Debug)]
+pub struct AnimationFinished {
+    pub entity: Entity,
+    pub clip: String,
+}
+
+/// Fired when a clip reaches the frame of one of its `ClipEvent`s
+#[derive(Event, Debug)]
+pub struct AnimationEvent {
+    pub entity: Entity,
+    pub clip: String,
+    pub name: String,
+}
+
+#[derive(Component, Deref, DerefMut)]
+pub struct AnimationTimer(pub Timer);
+
+/// Sheet of the `CHARACTER_ANIMATION_SET`, available once the set is loaded
 #[derive(Resource)]
 pub struct CharacterSpriteSheet {
     pub texture_atlas_layout: Handle<TextureAtlasLayout>,
@@ -35,79 +320,217 @@ pub struct AnimationPlugin;
 
 impl Plugin for AnimationPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, setup_animations)
-            .add_systems(Update, (animate_sprite_system, update_animation_direction));
+        app.init_asset::<AnimationSet>()
+            .init_asset_loader::<AnimationSetLoader>()
+            .init_resource::<AnimationLibrary>()
+            .add_observer(on_animation_finished)
+            .add_observer(on_animation_event)
+            .add_systems(Startup, load_animation_sets)
+            .add_systems(
+                Update,
+                (
+                    update_animation_library,
+                    reload_animation_sets,
+                    animate_sprite_system,
+                )
+                    .chain(),
+            );
     }
 }
 
-fn setup_animations(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+fn load_animation_sets(mut library: ResMut<AnimationLibrary>, asset_server: Res<AssetServer>) {
+    library.folder = NamedFolder::load(&asset_server, ANIMATION_SETS_DIR);
+}
+
+/// Names the sets of the animations folder, again whenever files are added to it
+fn update_animation_library(
+    mut events: MessageReader<AssetEvent<LoadedFolder>>,
+    mut library: ResMut<AnimationLibrary>,
+    folders: Res<Assets<LoadedFolder>>,
 ) {
-    let texture_handle = asset_server.load("body_dressed.png");
-    let layout = TextureAtlasLayout::from_grid(UVec2::new(64, 64), 9, 4, None, None);
-    let texture_atlas_layout_handle = texture_atlas_layouts.add(layout);
-
-    commands.insert_resource(CharacterSpriteSheet {
-        texture_atlas_layout: texture_atlas_layout_handle,
-        texture: texture_handle,
-    });
-
-    commands.insert_resource(CharacterAnimations {
-        walk_down: Animation {
-            first: 18,
-            last: 26,
-        },
-        walk_up: Animation { first: 0, last: 8 },
-        walk_left: Animation { first: 9, last: 17 },
-        walk_right: Animation {
-            first: 27,
-            last: 35,
-        },
-    });
-}
-
-fn update_animation_direction(
-    mut query: Query<(&mut Animation, &AnimationDirection), Changed<AnimationDirection>>,
-    animations: Res<CharacterAnimations>,
+    if !library.folder.is_changed(&mut events) {
+        return;
+    }
+
+    let Some(sets) = library
+        .folder
+        .read::<AnimationSet>(&folders, ANIMATION_SET_EXTENSION)
+    else {
+        return;
+    };
+
+    info!("animation sets: {:?}", sets.keys().collect::<Vec<_>>());
+    library.sets = sets;
+}
+
+/// Publishes the character sheet and restarts the clips of edited sets
+fn reload_animation_sets(
+    mut events: MessageReader<AssetEvent<AnimationSet>>,
+    library: Res<AnimationLibrary>,
+    sets: Res<Assets<AnimationSet>>,
+    mut controllers_q: Query<&mut AnimationController>,
+    mut commands: Commands,
 ) {
-    for (mut animation, direction) in query.iter_mut() {
-        match direction {
-            AnimationDirection::Up => {
-                animation.first = animations.walk_up.first;
-                animation.last = animations.walk_up.last;
-            }
-            AnimationDirection::Down => {
-                animation.first = animations.walk_down.first;
-                animation.last = animations.walk_down.last;
-            }
-            AnimationDirection::Left => {
-                animation.first = animations.walk_left.first;
-                animation.last = animations.walk_left.last;
-            }
-            AnimationDirection::Right => {
-                animation.first = animations.walk_right.first;
-                animation.last = animations.walk_right.last;
+    for event in events.read() {
+        let (AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id }) = *event
+        else {
+            continue;
+        };
+
+        let Some((name, _)) = library.sets.iter().find(|(_, handle)| handle.id() == id) else {
+            continue;
+        };
+
+        let Some(set) = sets.get(id) else {
+            continue;
+        };
+
+        if name == CHARACTER_ANIMATION_SET {
+            commands.insert_resource(CharacterSpriteSheet {
+                texture_atlas_layout: set.texture_atlas_layout.clone(),
+                texture: set.texture.clone(),
+            });
+        }
+
+        if matches!(event, AssetEvent::Modified { .. }) {
+            info!("reloaded animation set {:?}", name);
+
+            for mut controller in &mut controllers_q {
+                if controller.set != *name {
+                    continue;
+                }
+
+                // The clip may be gone or shorter than the current frame
+                let clip = if set.clips.contains_key(&controller.clip) {
+                    controller.clip.clone()
+                } else {
+                    set.default_clip.clone()
+                };
+                controller.switch_to(clip);
             }
         }
     }
 }
 
+fn on_animation_finished(finished: On<AnimationFinished>) {
+    debug!("{:?} finished playing {:?}", finished.entity, finished.clip);
+}
+
+fn on_animation_event(event: On<AnimationEvent>) {
+    debug!(
+        "{:?} reached {:?} in {:?}",
+        event.entity, event.name, event.clip
+    );
+}
+
 fn animate_sprite_system(
     time: Res<Time>,
-    query: Query<(&Animation, &mut AnimationTimer, &mut Sprite)>,
+    library: Res<AnimationLibrary>,
+    sets: Res<Assets<AnimationSet>>,
+    query: Query<(
+        Entity,
+        &mut AnimationController,
+        &AnimationDirection,
+        &mut AnimationTimer,
+        &mut Sprite,
+    )>,
+    mut commands: Commands,
 ) {
-    for (animation, mut timer, mut sprite) in query {
-        timer.tick(time.delta());
-        if timer.just_finished() {
-            if let Some(atlas) = &mut sprite.texture_atlas {
-                atlas.index = if atlas.index == animation.last {
-                    animation.first
+    for (entity, mut controller, direction, mut timer, mut sprite) in query {
+        let Some(set) = library
+            .get(&controller.set)
+            .and_then(|handle| sets.get(handle))
+        else {
+            continue;
+        };
+
+        // 1. Transitions
+        if let Some(requested) = controller.requested.clone() {
+            let busy = set
+                .clips
+                .get(&controller.clip)
+                .is_some_and(|clip| clip.mode == ClipMode::Once && !controller.finished);
+
+            if !busy {
+                if requested == controller.clip {
+                    controller.requested = None;
+                } else if set.can_transition(&controller.clip, &requested) {
+                    controller.requested = None;
+                    controller.switch_to(requested);
+                } else if set.can_transition(&controller.clip, &set.default_clip)
+                    && set.can_transition(&set.default_clip, &requested)
+                {
+                    // Keep the request for when the default clip is playing
+                    controller.switch_to(set.default_clip.clone());
                 } else {
-                    atlas.index + 1
-                };
+                    warn!(
+                        "{:?}: no transition from {:?} to {:?}",
+                        entity, controller.clip, requested
+                    );
+                    controller.requested = None;
+                }
             }
         }
+
+        let Some(clip) = set.clips.get(&controller.clip) else {
+            continue;
+        };
+
+        let frame_count = clip.frame_count();
+
+        // 2. Frames
+        let mut entered_frame = false;
+
+        if controller.restart {
+            controller.restart = false;
+            timer.set_duration(clip.duration(0));
+            timer.reset();
+            entered_frame = true;
+        } else if !controller.finished && timer.tick(time.delta()).just_finished() {
+            if controller.frame + 1 < frame_count {
+                controller.frame += 1;
+                entered_frame = true;
+            } else if clip.mode == ClipMode::Loop {
+                controller.frame = 0;
+                entered_frame = true;
+            } else {
+                controller.finished = true;
+
+                if let Some(next) = &clip.next {
+                    controller.play(next);
+                }
+
+                commands.trigger(AnimationFinished {
+                    entity,
+                    clip: controller.clip.clone(),
+                });
+            }
+
+            if entered_frame {
+                timer.set_duration(clip.duration(controller.frame));
+            }
+        }
+
+        if entered_frame {
+            for event in clip.events.iter().filter(|e| e.frame == controller.frame) {
+                commands.trigger(AnimationEvent {
+                    entity,
+                    clip: controller.clip.clone(),
+                    name: event.name.clone(),
+                });
+            }
+        }
+
+        // Only touch the sprite on frame changes, `Changed<Sprite>` drives the layers
+        let column = clip.frames.first + controller.frame.min(frame_count - 1);
+        let Some(index) = set.atlas_index(*direction, column) else {
+            continue;
+        };
+
+        if sprite.texture_atlas.as_ref().map(|atlas| atlas.index) != Some(index)
+            && let Some(atlas) = &mut sprite.texture_atlas
+        {
+            atlas.index = index;
+        }
     }
 }
@@ -0,0 +1,244 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+    sprite::Anchor,
+};
+use rand::{Rng, seq::SliceRandom};
+use serde::Deserialize;
+
+use crate::{agent::Agent, clock::DayNightTint};
+
+const APPEARANCE_CATALOG: &str = "characters.appearance.json";
+const APPEARANCE_CATALOG_EXTENSION: &str = "appearance.json";
+
+pub struct AppearancePlugin;
+
+impl Plugin for AppearancePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<AppearanceCatalog>()
+            .init_asset_loader::<AppearanceCatalogLoader>()
+            .init_resource::<AppearanceCatalogHandle>()
+            .add_observer(on_add_appearance)
+            .add_systems(Startup, load_appearance_catalog)
+            .add_systems(Update, assign_appearances)
+            .add_systems(PostUpdate, sync_appearance_layers);
+    }
+}
+
+/// One LPC-style sheet sharing the layout of the character animation set
+#[derive(Deserialize, Clone, Debug)]
+pub struct AppearanceLayer {
+    pub sheet: String,
+    #[serde(default)]
+    pub tint: Option<[f32; 3]>,
+}
+
+impl AppearanceLayer {
+    pub fn color(&self) -> Color {
+        self.tint
+            .map_or(Color::WHITE, |[r, g, b]| Color::srgb(r, g, b))
+    }
+}
+
+/// Layers composing an agent, drawn bottom to top
+#[derive(Component, Clone, Debug)]
+pub struct Appearance {
+    pub body: AppearanceLayer,
+    pub clothing: Option<AppearanceLayer>,
+    pub hair: Option<AppearanceLayer>,
+    pub accessory: Option<AppearanceLayer>,
+}
+
+impl Appearance {
+    fn overlays(&self) -> impl Iterator<Item = &AppearanceLayer> {
+        [&self.clothing, &self.hair, &self.accessory]
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// Child sprite drawing one overlay of the parent's `Appearance`
+#[derive(Component)]
+pub struct AppearanceLayerSprite {
+    tint: Color,
+}
+
+/// Sheets agents pick their layers from, read from `APPEARANCE_CATALOG`
+#[derive(Asset, TypePath, Deserialize, Debug)]
+pub struct AppearanceCatalog {
+    pub body: Vec<AppearanceLayer>,
+    #[serde(default)]
+    pub clothing: Vec<AppearanceLayer>,
+    #[serde(default)]
+    pub hair: Vec<AppearanceLayer>,
+    #[serde(default)]
+    pub accessory: Vec<AppearanceLayer>,
+}
+
+impl Default for AppearanceCatalog {
+    fn default() -> Self {
+        Self {
+            body: vec![AppearanceLayer {
+                sheet: "body_dressed.png".to_string(),
+                tint: None,
+            }],
+            clothing: vec![],
+            hair: vec![],
+            accessory: vec![],
+        }
+    }
+}
+
+impl AppearanceCatalog {
+    pub fn random_appearance(&self) -> Appearance {
+        let mut rnd = rand::thread_rng();
+
+        let body = self
+            .body
+            .choose(&mut rnd)
+            .cloned()
+            .unwrap_or_else(|| AppearanceCatalog::default().body[0].clone());
+
+        // Optional layers may also be left empty
+        let mut pick_optional = |options: &[AppearanceLayer]| {
+            if options.is_empty() || rnd.gen_range(0..=options.len()) == options.len() {
+                None
+            } else {
+                options.choose(&mut rnd).cloned()
+            }
+        };
+
+        Appearance {
+            body,
+            clothing: pick_optional(&self.clothing),
+            hair: pick_optional(&self.hair),
+            accessory: pick_optional(&self.accessory),
+        }
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct AppearanceCatalogLoader;
+
+impl AssetLoader for AppearanceCatalogLoader {
+    type Asset = AppearanceCatalog;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<AppearanceCatalog, anyhow::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let catalog = serde_json::from_slice::<AppearanceCatalog>(&bytes)?;
+        if catalog.body.is_empty() {
+            anyhow::bail!("appearance catalog has no body");
+        }
+
+        Ok(catalog)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[APPEARANCE_CATALOG_EXTENSION]
+    }
+}
+
+#[derive(Resource, Default)]
+struct AppearanceCatalogHandle(Handle<AppearanceCatalog>);
+
+fn load_appearance_catalog(
+    mut catalog: ResMut<AppearanceCatalogHandle>,
+    asset_server: Res<AssetServer>,
+) {
+    catalog.0 = asset_server.load(APPEARANCE_CATALOG);
+}
+
+/// Agents without an `Appearance` get a random one once the catalog is
+/// loaded, from the default catalog when it cannot be
+fn assign_appearances(
+    query: Query<Entity, (With<Agent>, Without<Appearance>)>,
+    catalog: Res<AppearanceCatalogHandle>,
+    catalogs: Res<Assets<AppearanceCatalog>>,
+    asset_server: Res<AssetServer>,
+    mut commands: Commands,
+) {
+    if query.is_empty() {
+        return;
+    }
+
+    let fallback;
+    let catalog = match catalogs.get(&catalog.0) {
+        Some(loaded) => loaded,
+        None if asset_server.load_state(&catalog.0).is_failed() => {
+            fallback = AppearanceCatalog::default();
+            &fallback
+        }
+        None => return,
+    };
+
+    for entity in &query {
+        commands.entity(entity).insert(catalog.random_appearance());
+    }
+}
+
+fn on_add_appearance(
+    add: On<Add, Appearance>,
+    mut query: Query<(&Appearance, &mut Sprite)>,
+    asset_server: Res<AssetServer>,
+    mut commands: Commands,
+) {
+    let Ok((appearance, mut sprite)) = query.get_mut(add.entity) else {
+        return;
+    };
+
+    // The agent's own sprite is the body and drives the animation
+    sprite.image = asset_server.load(&appearance.body.sheet);
+    let Some(body_atlas) = sprite.texture_atlas.clone() else {
+        return;
+    };
+
+    for (order, layer) in appearance.overlays().enumerate() {
+        let mut layer_sprite =
+            Sprite::from_atlas_image(asset_server.load(&layer.sheet), body_atlas.clone());
+        layer_sprite.color = layer.color();
+
+        commands.spawn((
+            AppearanceLayerSprite {
+                tint: layer.color(),
+            },
+            layer_sprite,
+            Anchor::BOTTOM_CENTER,
+            Transform::from_xyz(0.0, 0.0, 0.01 * (order + 1) as f32),
+            ChildOf(add.entity),
+        ));
+    }
+}
+
+/// Keeps the overlays on the same frame, flip, alpha and day/night tint as the body
+fn sync_appearance_layers(
+    bodies_q: Query<(&Sprite, &Children), (Changed<Sprite>, Without<AppearanceLayerSprite>)>,
+    mut layers_q: Query<(&mut Sprite, &AppearanceLayerSprite)>,
+    tint: Res<DayNightTint>,
+) {
+    for (body_sprite, children) in &bodies_q {
+        let Some(body_atlas) = &body_sprite.texture_atlas else {
+            continue;
+        };
+
+        for child in children.iter() {
+            let Ok((mut layer_sprite, layer)) = layers_q.get_mut(child) else {
+                continue;
+            };
+
+            if let Some(atlas) = &mut layer_sprite.texture_atlas {
+                atlas.index = body_atlas.index;
+            }
+            layer_sprite.flip_x = body_sprite.flip_x;
+            layer_sprite.color = tint.apply(layer.tint).with_alpha(body_sprite.color.alpha());
+        }
+    }
+}
@@ -1,224 +1,713 @@
 use bevy::prelude::*;
-use bevy::tasks::IoTaskPool;
+use bevy::tasks::{IoTaskPool, Task, block_on, poll_once};
 use crossbeam_channel::{Receiver, Sender};
 use libsql::Builder;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use crate::{
+    facts::FactGatherer,
+    llm::{LlmBackend, LlmConfig, LlmRequest, Turn, unblock},
+    memory,
+    persona::situation_prompt,
+};
+
+const DB_PATH: &str = "local.db";
+
+/// Requests running at once, the others wait in the queue
+const MAX_CONCURRENT_REQUESTS: usize = 2;
+
+/// Earlier turns sent to backends that take the history, older ones are
+/// summarised into memories
+const MAX_HISTORY_TURNS: usize = 8;
+
+/// Longest context kept between turns. A longer one is dropped rather than
+/// cut, since its start holds the system prompt: the next turn starts over
+/// from the system prompt, the memories and the history.
+const MAX_CONTEXT_TOKENS: usize = 2048;
 
 pub struct BackgroundPlugin;
 
 impl Plugin for BackgroundPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Startup,
-            (
-                setup_channel,
-                // Ensure the channel is set up before spawning tasks.
-                spawn_task.after(setup_channel),
-            ),
-        );
+        app.insert_resource(LlmService::new(LlmConfig::load().backend()))
+            .add_observer(on_dialogue_request)
+            .add_observer(on_dialogue_queued)
+            .add_observer(on_dialogue_token)
+            .add_observer(on_dialogue_completed)
+            .add_observer(on_dialogue_failed)
+            .add_observer(on_dialogue_cancelled)
+            .add_systems(Startup, setup_database)
+            .add_systems(Update, pump_llm_service);
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub id: usize,
-    pub content: String,
+pub type RequestId = usize;
+
+/// Asks `npc` to answer `prompt` within its conversation. Replies come back as
+/// `DialogueToken`s and a `DialogueCompleted` tagged with the request ID.
+#[derive(Event, Debug, Clone)]
+pub struct DialogueRequest {
+    pub npc: Entity,
+    pub prompt: String,
+    /// Starts with the `ConversationId` of `npc`, which keeps the memories of
+    /// the conversation
+    pub conversation_id: String,
+}
+
+/// Fired when a `DialogueRequest` event gets its ID, which the later events
+/// of the request carry
+#[derive(Event, Debug)]
+pub struct DialogueQueued {
+    pub request: RequestId,
+    pub npc: Entity,
+}
+
+/// Piece of the reply, in order
+#[derive(Event, Debug)]
+pub struct DialogueToken {
+    pub request: RequestId,
+    pub npc: Entity,
+    pub text: String,
+}
+
+#[derive(Event, Debug)]
+pub struct DialogueCompleted {
+    pub request: RequestId,
+    pub npc: Entity,
+    pub response: String,
+}
+
+#[derive(Event, Debug)]
+pub struct DialogueFailed {
+    pub request: RequestId,
+    pub npc: Entity,
+    pub error: String,
 }
 
+#[derive(Event, Debug)]
+pub struct DialogueCancelled {
+    pub request: RequestId,
+    pub npc: Entity,
+}
+
+/// Conversation an NPC keeps across turns and runs, named after its persona
+#[derive(Component, Debug, Clone)]
+pub struct ConversationId(pub String);
+
+/// What a worker reports about its request
+enum LlmUpdate {
+    Token(String),
+    Completed(String),
+    Failed(String),
+}
+
+struct QueuedRequest {
+    id: RequestId,
+    request: DialogueRequest,
+    /// System prompt of the NPC's persona
+    system: String,
+    /// Its "RIGHT NOW" section alone
+    situation: String,
+}
+
+/// Memory work run by the workers when no dialogue waits
+#[derive(PartialEq)]
+enum MemoryJob {
+    /// Summary of the turns the history of a conversation no longer carries
+    Consolidate { conversation_id: String },
+    /// Summary of a chat between NPCs, kept by the NPC the conversation
+    /// starts with
+    Chat {
+        conversation_id: String,
+        transcript: String,
+    },
+}
+
+impl MemoryJob {
+    async fn run(
+        self,
+        conn: &libsql::Connection,
+        backend: &Arc<dyn LlmBackend>,
+    ) -> anyhow::Result<()> {
+        match self {
+            MemoryJob::Consolidate { conversation_id } => {
+                memory::consolidate(conn, backend, &conversation_id, MAX_HISTORY_TURNS).await
+            }
+            MemoryJob::Chat {
+                conversation_id,
+                transcript,
+            } => memory::remember_chat(conn, backend, &conversation_id, transcript).await,
+        }
+    }
+}
+
+/// Connection shared by the workers, served once its tables exist
+enum Database {
+    Closed,
+    Opening(Task<anyhow::Result<libsql::Connection>>),
+    Ready(libsql::Connection),
+    Failed(String),
+}
+
+struct InFlightRequest {
+    npc: Entity,
+    conversation_id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Queue of LLM requests, run by background workers a few at a time
 #[derive(Resource)]
-pub struct MessageChannel {
-    pub sender: Sender<Message>,
-    pub receiver: Receiver<Message>,
-}
-
-fn setup_channel(mut commands: Commands) {
-    let (sender, receiver) = crossbeam_channel::unbounded();
-    commands.insert_resource(MessageChannel { sender, receiver });
-}
-
-const LLM_MODEL: &str = "phi3:3.8b";
-const PROMPT: &str = "
-<|system|>
-SYSTEM: You are an NPC in a medieval fantasy RPG. You always speak in character. 
-
-NPC PROFILE: 
-Name: Eldrin 
-Role: Blacksmith 
-Personality: Cynical, blunt, tired 
-Loyalty: Iron Guild 
-Mood: Irritated 
-
-WORLD FACTS: 
-- Baron raised taxes yesterday 
-- Bandits near the north road 
-- You are out of ores and ingots
-
-RULES: 
-- Speak in first person 
-- Medieval tone 
-- No modern words 
-- No explanations 
-- Max 2 sentences 
-
-AVOID: 
-- 'I can help' 
-- 'Perhaps' 
-- 'It seems' 
-- 'As a' 
-
-IMPORTANT: 
-Respond with ONLY the NPC's spoken dialogue. 
-Do NOT describe actions. 
-Do NOT explain your reasoning.
-<|end|>
-
-<|user|>
-Greetings Blacksmith! I have an urgent order of Iron tools for the bridge building.
-<|end|>
-
-<|assistant|>
-";
-
-#[derive(Serialize)]
-struct GenerationRequest {
-    model: String,
-    prompt: String,
-    stream: bool,
-    temperature: f32,
-    top_p: f32,
-    num_predict: usize,
-    repeat_penalty: f32,
-    stop: Vec<String>,
-}
-
-#[derive(Deserialize, Debug)]
-struct GenerationResponse {
-    response: String,
-    context: Option<Vec<u32>>,
-    done: bool,
-}
-
-fn spawn_task(channel: Res<MessageChannel>) {
-    let pool = IoTaskPool::get();
-
-    let _sender = channel.sender.clone();
-    let msg_id = 1;
-    // Spawn a task on the async compute pool
-    pool.spawn(async move {
-        let db = Builder::new_local("local.db")
-            .build()
-            .await
-            .expect("build new local.db failed");
-        let conn = db.connect().expect("connect failed");
-
-        println!("conn: {:?}", conn);
-
-        let testing = conn
-            .execute(
-                "
-             CREATE TABLE IF NOT EXISTS chat_context (
-                id INTEGER PRIMARY KEY,
-                context TEXT NOT NULL,
-                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
-              )
-            ",
-                (),
-            )
-            .await
-            .expect("error running query");
-
-        println!("result: {:?}", testing);
-
-        let req = GenerationRequest {
-            model: LLM_MODEL.to_string(),
-            prompt: PROMPT.to_string(),
-            stream: true,
-            temperature: 0.6,
-            top_p: 0.9,
-            num_predict: 80,
-            repeat_penalty: 1.1,
-            stop: vec!["<|end|>:".to_string()],
+pub struct LlmService {
+    queue: VecDeque<QueuedRequest>,
+    in_flight: HashMap<RequestId, InFlightRequest>,
+    jobs: VecDeque<MemoryJob>,
+    running_jobs: Arc<AtomicUsize>,
+    next_id: RequestId,
+    pub max_concurrent: usize,
+    backend: Arc<dyn LlmBackend>,
+    database: Database,
+    sender: Sender<(RequestId, LlmUpdate)>,
+    receiver: Receiver<(RequestId, LlmUpdate)>,
+}
+
+impl LlmService {
+    pub fn new(backend: Box<dyn LlmBackend>) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self {
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            jobs: VecDeque::new(),
+            running_jobs: Arc::new(AtomicUsize::new(0)),
+            next_id: 0,
+            max_concurrent: MAX_CONCURRENT_REQUESTS,
+            backend: backend.into(),
+            database: Database::Closed,
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn submit(
+        &mut self,
+        request: DialogueRequest,
+        system: String,
+        situation: String,
+    ) -> RequestId {
+        self.next_id += 1;
+        self.queue.push_back(QueuedRequest {
+            id: self.next_id,
+            request,
+            system,
+            situation,
+        });
+        self.next_id
+    }
+
+    /// Has the NPC owning `conversation_id` remember a chat it had with
+    /// another NPC
+    pub fn remember_chat(&mut self, conversation_id: String, transcript: String) {
+        self.jobs.push_back(MemoryJob::Chat {
+            conversation_id,
+            transcript,
+        });
+    }
+
+    /// Opens the database at `path` and creates its tables. Requests wait in
+    /// the queue until it is ready.
+    pub fn open_database(&mut self, path: &str) {
+        let path = path.to_string();
+        self.database = Database::Opening(IoTaskPool::get().spawn(async move {
+            let db = Builder::new_local(path).build().await?;
+            let conn = db.connect()?;
+            create_tables(&conn).await?;
+            Ok(conn)
+        }));
+    }
+
+    fn poll_database(&mut self) {
+        let Database::Opening(task) = &mut self.database else {
+            return;
         };
 
-        let url = "http://localhost:11434/api/generate";
-
-        if let Ok(body) = serde_json::to_string(&req) {
-            let request = ehttp::Request::post(url, body.as_bytes().to_vec())
-                .with_timeout(Some(Duration::from_secs(60 * 30)));
-
-            let response = ehttp::fetch_blocking(&request);
-            match response {
-                Ok(res) => {
-                    for line in res.text().unwrap().split('\n') {
-                        let p_line: GenerationResponse =
-                            serde_json::from_str(line).expect("error deserializing http response");
-
-                        println!("line: {:?}", p_line);
-
-                        if p_line.done {
-                            if let Some(raw_context) = p_line.context {
-
-                                let mut context = Context::from_ollama(raw_context);
-                                context.truncate_last(2048);
-
-                                let testing = conn
-                                    .execute(
-                                        "INSERT INTO chat_context (id, context) VALUES (?1, ?2)",
-                                        (
-                                            msg_id.to_string(),
-                                            context.to_json().expect("Fail to convert context to json"),
-                                        ),
-                                    )
-                                    .await
-                                    .expect("error running insert query");
-
-                                println!("result: {:?}", testing);
-                            }
-                            return;
-                        } else {
-                            _sender
-                                .send(Message {
-                                    id: msg_id,
-                                    content: p_line.response,
-                                })
-                                .expect("error sending msg through channel");
-                        }
+        match block_on(poll_once(task)) {
+            Some(Ok(conn)) => self.database = Database::Ready(conn),
+            Some(Err(err)) => {
+                error!("cannot open {}: {:?}", DB_PATH, err);
+                self.database = Database::Failed(format!("{:?}", err));
+            }
+            None => {}
+        }
+    }
+
+    /// Requests waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Drops a queued request, or ignores the rest of a running one. Returns
+    /// the NPC of the request when it was still pending.
+    pub fn cancel(&mut self, id: RequestId) -> Option<Entity> {
+        if let Some(idx) = self.queue.iter().position(|queued| queued.id == id) {
+            return self.queue.remove(idx).map(|queued| queued.request.npc);
+        }
+
+        let in_flight = self.in_flight.remove(&id)?;
+        in_flight.cancelled.store(true, Ordering::Relaxed);
+        Some(in_flight.npc)
+    }
+
+    /// Starts queued requests while there is room, then memory jobs. Turns of
+    /// the same conversation run one at a time, each one continuing the
+    /// previous.
+    fn start_queued(&mut self) {
+        let Database::Ready(conn) = &self.database else {
+            return;
+        };
+        let conn = conn.clone();
+
+        let mut idx = 0;
+
+        while self.in_flight.len() < self.max_concurrent && idx < self.queue.len() {
+            let busy = self.in_flight.values().any(|in_flight| {
+                in_flight.conversation_id == self.queue[idx].request.conversation_id
+            });
+
+            if busy {
+                idx += 1;
+                continue;
+            }
+
+            let Some(queued) = self.queue.remove(idx) else {
+                break;
+            };
+
+            let id = queued.id;
+            let cancelled = Arc::new(AtomicBool::new(false));
+            self.in_flight.insert(
+                id,
+                InFlightRequest {
+                    npc: queued.request.npc,
+                    conversation_id: queued.request.conversation_id.clone(),
+                    cancelled: cancelled.clone(),
+                },
+            );
+
+            let sender = self.sender.clone();
+            let backend = self.backend.clone();
+            let conn = conn.clone();
+            IoTaskPool::get()
+                .spawn(async move {
+                    let update = match run_turn(queued, &conn, &backend, &cancelled, &sender).await
+                    {
+                        Ok(response) => LlmUpdate::Completed(response),
+                        Err(err) => LlmUpdate::Failed(format!("{:?}", err)),
+                    };
+                    let _ = sender.send((id, update));
+                })
+                .detach();
+        }
+
+        // Memory jobs only take the room dialogue leaves
+        while self.queue.is_empty()
+            && self.in_flight.len() + self.running_jobs.load(Ordering::Relaxed)
+                < self.max_concurrent
+        {
+            let Some(job) = self.jobs.pop_front() else {
+                break;
+            };
+
+            self.running_jobs.fetch_add(1, Ordering::Relaxed);
+            let running_jobs = self.running_jobs.clone();
+            let backend = self.backend.clone();
+            let conn = conn.clone();
+            IoTaskPool::get()
+                .spawn(async move {
+                    if let Err(err) = job.run(&conn, &backend).await {
+                        warn!("memory job failed: {:?}", err);
                     }
+                    running_jobs.fetch_sub(1, Ordering::Relaxed);
+                })
+                .detach();
+        }
+    }
+}
+
+fn setup_database(mut llm: ResMut<LlmService>) {
+    llm.open_database(DB_PATH);
+}
+
+async fn create_tables(conn: &libsql::Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS conversation_turns (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            conversation_id TEXT NOT NULL,
+            prompt TEXT NOT NULL,
+            response TEXT NOT NULL,
+            context TEXT NOT NULL,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        ",
+        (),
+    )
+    .await?;
+
+    memory::create_tables(conn).await
+}
+
+fn on_dialogue_request(
+    request: On<DialogueRequest>,
+    facts: FactGatherer,
+    mut llm: ResMut<LlmService>,
+    mut commands: Commands,
+) {
+    let npc = request.npc;
+    let situation = facts.ranked(npc);
+    let system = facts.system_prompt(npc, &situation);
+    let id = llm.submit(
+        request.event().clone(),
+        system,
+        situation_prompt(&situation),
+    );
+    commands.trigger(DialogueQueued { request: id, npc });
+}
+
+fn on_dialogue_queued(queued: On<DialogueQueued>) {
+    debug!(
+        "{:?} queued dialogue request {}",
+        queued.npc, queued.request
+    );
+}
+
+fn on_dialogue_token(token: On<DialogueToken>) {
+    trace!(
+        "{:?} request {}: {:?}",
+        token.npc, token.request, token.text
+    );
+}
+
+fn on_dialogue_completed(completed: On<DialogueCompleted>) {
+    info!(
+        "{:?} answered request {}: {}",
+        completed.npc, completed.request, completed.response
+    );
+}
+
+fn on_dialogue_failed(failed: On<DialogueFailed>) {
+    warn!(
+        "{:?} dialogue request {} failed: {}",
+        failed.npc, failed.request, failed.error
+    );
+}
+
+fn on_dialogue_cancelled(cancelled: On<DialogueCancelled>) {
+    debug!(
+        "{:?} dialogue request {} cancelled",
+        cancelled.npc, cancelled.request
+    );
+}
+
+/// Starts queued requests and turns worker updates into dialogue events
+fn pump_llm_service(mut llm: ResMut<LlmService>, mut commands: Commands) {
+    llm.poll_database();
+
+    // Without a database there is no conversation to continue
+    if let Database::Failed(error) = &llm.database {
+        let error = error.clone();
+        for queued in llm.queue.drain(..) {
+            commands.trigger(DialogueFailed {
+                request: queued.id,
+                npc: queued.request.npc,
+                error: format!("cannot open {}: {}", DB_PATH, error),
+            });
+        }
+    }
+
+    let updates: Vec<(RequestId, LlmUpdate)> = llm.receiver.try_iter().collect();
+
+    for (request, update) in updates {
+        // Cancelled requests are no longer in flight, their updates are dropped
+        let Some(in_flight) = llm.in_flight.get(&request) else {
+            continue;
+        };
+        let npc = in_flight.npc;
+
+        match update {
+            LlmUpdate::Token(text) => commands.trigger(DialogueToken { request, npc, text }),
+            LlmUpdate::Completed(response) => {
+                let Some(in_flight) = llm.in_flight.remove(&request) else {
+                    continue;
+                };
+                commands.trigger(DialogueCompleted {
+                    request,
+                    npc,
+                    response,
+                });
+
+                // Summaries wait for the reply to be out
+                let job = MemoryJob::Consolidate {
+                    conversation_id: in_flight.conversation_id,
+                };
+                if !llm.jobs.contains(&job) {
+                    llm.jobs.push_back(job);
                 }
-                Err(err) => {
-                    println!("HTTP Error: {:?}", err);
-                }
             }
+            LlmUpdate::Failed(error) => {
+                llm.in_flight.remove(&request);
+                commands.trigger(DialogueFailed {
+                    request,
+                    npc,
+                    error,
+                });
+            }
+        }
+    }
+
+    llm.start_queued();
+}
+
+/// Latest turns of the conversation, oldest first, and the context left by
+/// the last one
+async fn load_history(
+    conn: &libsql::Connection,
+    conversation_id: &str,
+) -> anyhow::Result<(Vec<Turn>, Option<Context>)> {
+    let mut rows = conn
+        .query(
+            "SELECT prompt, response, context FROM conversation_turns WHERE conversation_id = ?1 ORDER BY id DESC LIMIT ?2",
+            (conversation_id, MAX_HISTORY_TURNS as i64),
+        )
+        .await?;
+
+    let mut history = Vec::new();
+    let mut context = None;
+
+    while let Some(row) = rows.next().await? {
+        if context.is_none() {
+            context = Some(Context::from_json(row.get_str(2)?)?);
         }
+        history.push(Turn {
+            prompt: row.get_str(0)?.to_string(),
+            response: row.get_str(1)?.to_string(),
+        });
+    }
+
+    history.reverse();
+    Ok((history, context))
+}
+
+/// Runs one turn of the conversation, streaming tokens back, and returns the
+/// whole response
+async fn run_turn(
+    queued: QueuedRequest,
+    conn: &libsql::Connection,
+    backend: &Arc<dyn LlmBackend>,
+    cancelled: &Arc<AtomicBool>,
+    sender: &Sender<(RequestId, LlmUpdate)>,
+) -> anyhow::Result<String> {
+    let QueuedRequest {
+        id,
+        request,
+        system,
+        situation,
+    } = queued;
+
+    let (history, context) = load_history(conn, &request.conversation_id).await?;
+    let memories = memory::recall(conn, backend, &request.conversation_id, &request.prompt).await?;
+
+    let llm_request = LlmRequest {
+        system: memory::with_memories(system, &memories),
+        recap: memory::with_memories(situation, &memories),
+        history,
+        prompt: request.prompt.clone(),
+        context: context.map(|context| context.tokens),
+        ..default()
+    };
+
+    let (cancelled, sender) = (cancelled.clone(), sender.clone());
+    let response = unblock(backend, move |backend| {
+        backend.generate(&llm_request, &mut |token| {
+            if cancelled.load(Ordering::Relaxed) {
+                anyhow::bail!("cancelled");
+            }
+            sender.send((id, LlmUpdate::Token(token.to_string())))?;
+            Ok(())
+        })
     })
-    .detach();
+    .await?;
+
+    let mut context = Context {
+        tokens: response.context.unwrap_or_default(),
+    };
+    if context.tokens.len() > MAX_CONTEXT_TOKENS {
+        context.tokens.clear();
+    }
+
+    // Turns are appended, the latest context continues the conversation
+    conn.execute(
+        "INSERT INTO conversation_turns (conversation_id, prompt, response, context) VALUES (?1, ?2, ?3, ?4)",
+        (
+            request.conversation_id.as_str(),
+            request.prompt.as_str(),
+            response.text.as_str(),
+            context.to_json()?,
+        ),
+    )
+    .await?;
+
+    Ok(response.text)
 }
 
+/// Ollama context of a conversation, empty for the other backends
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Context {
     tokens: Vec<u32>,
 }
 
 impl Context {
-    pub fn from_ollama(tokens: Vec<u32>) -> Self {
-        Self {
-            tokens: tokens.into_iter().map(|t| t as u32).collect(),
-        }
-    }
-
     pub fn to_json(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(&self.tokens)?)
     }
 
     pub fn from_json(s: &str) -> anyhow::Result<Self> {
-        Ok(serde_json::from_str(s)?)
+        Ok(Self {
+            tokens: serde_json::from_str(s)?,
+        })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Received {
+        tokens: Vec<String>,
+        completed: Vec<(RequestId, String)>,
+    }
+
+    fn app(replies: &[&str]) -> App {
+        let mut llm = LlmService::new(
+            LlmConfig::Scripted {
+                replies: replies.iter().map(|reply| reply.to_string()).collect(),
+            }
+            .backend(),
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+
+        llm.open_database(":memory:");
+        app.insert_resource(llm)
+            .init_resource::<Received>()
+            .add_observer(|token: On<DialogueToken>, mut received: ResMut<Received>| {
+                received.tokens.push(token.text.clone());
+            })
+            .add_observer(
+                |completed: On<DialogueCompleted>, mut received: ResMut<Received>| {
+                    received
+                        .completed
+                        .push((completed.request, completed.response.clone()));
+                },
+            )
+            .add_systems(Update, pump_llm_service);
+        app
+    }
+
+    fn submit(app: &mut App, npc: Entity, prompt: &str) -> RequestId {
+        app.world_mut().resource_mut::<LlmService>().submit(
+            DialogueRequest {
+                npc,
+                prompt: prompt.to_string(),
+                conversation_id: "npc-test".to_string(),
+            },
+            "You are a test.".to_string(),
+            String::new(),
+        )
+    }
+
+    /// Updates the app until `request` completes
+    fn complete(app: &mut App, request: RequestId) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
 
-    pub fn truncate_last(&mut self, max_tokens: usize) {
-        if self.tokens.len() > max_tokens {
-            let start = self.tokens.len() - max_tokens;
-            self.tokens = self.tokens[start..].to_vec();
+        while Instant::now() < deadline {
+            app.update();
+
+            let received = app.world().resource::<Received>();
+            if let Some((_, response)) = received.completed.iter().find(|(id, _)| *id == request) {
+                return response.clone();
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("request {} did not complete", request);
+    }
+
+    #[test]
+    fn scripted_turns_stream_and_complete() {
+        let mut app = app(&["Well met, traveller.", "Farewell."]);
+        let npc = app.world_mut().spawn_empty().id();
+
+        let first = submit(&mut app, npc, "Hello!");
+        assert_eq!(complete(&mut app, first), "Well met, traveller.");
+
+        let received = app.world().resource::<Received>();
+        assert_eq!(received.tokens, ["Well ", "met, ", "traveller."]);
+
+        // The stored turn makes the history of the next one
+        let second = submit(&mut app, npc, "Goodbye!");
+        assert_eq!(complete(&mut app, second), "Farewell.");
+    }
+
+    #[test]
+    fn chat_memories_belong_to_the_conversation_owner() {
+        let mut app = app(&["You traded gossip about the harvest."]);
+        app.world_mut().resource_mut::<LlmService>().remember_chat(
+            "marta:tomas".to_string(),
+            "Tomas: Fine harvest!".to_string(),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            app.update();
+
+            let llm = app.world().resource::<LlmService>();
+            if llm.jobs.is_empty() && llm.running_jobs.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+            assert!(Instant::now() < deadline, "memory job did not run");
+            std::thread::sleep(Duration::from_millis(5));
         }
+
+        let llm = app.world().resource::<LlmService>();
+        let Database::Ready(conn) = &llm.database else {
+            panic!("database is not open");
+        };
+        let memories = block_on(memory::recall(
+            conn,
+            &llm.backend,
+            "marta",
+            "How was the harvest?",
+        ))
+        .unwrap();
+        assert_eq!(memories, ["You traded gossip about the harvest."]);
+
+        // The other side only remembers the chats queued for it
+        let memories = block_on(memory::recall(
+            conn,
+            &llm.backend,
+            "tomas",
+            "How was the harvest?",
+        ))
+        .unwrap();
+        assert!(memories.is_empty());
     }
 }
@@ -0,0 +1,54 @@
+use bevy::prelude::*;
+
+use crate::agent::SelectedAgent;
+
+pub struct CameraFollowPlugin;
+
+impl Plugin for CameraFollowPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CameraFollow>()
+            .add_systems(Update, (toggle_camera_follow, follow_camera_target).chain());
+    }
+}
+
+/// Entity the camera keeps centred on
+#[derive(Resource, Default)]
+pub struct CameraFollow(pub Option<Entity>);
+
+fn toggle_camera_follow(
+    input: Res<ButtonInput<KeyCode>>,
+    selected: Res<SelectedAgent>,
+    mut follow: ResMut<CameraFollow>,
+) {
+    if input.just_pressed(KeyCode::KeyF) {
+        follow.0 = match follow.0 {
+            Some(_) => None,
+            None => selected.0,
+        };
+    }
+}
+
+fn follow_camera_target(
+    mut follow: ResMut<CameraFollow>,
+    targets_q: Query<&Transform, Without<Camera2d>>,
+    mut camera: Single<&mut Transform, With<Camera2d>>,
+    time: Res<Time>,
+) {
+    const FOLLOW_SPEED: f32 = 5.0;
+
+    let Some(target) = follow.0 else {
+        return;
+    };
+
+    let Ok(target_transform) = targets_q.get(target) else {
+        follow.0 = None;
+        return;
+    };
+
+    let target_xy = target_transform.translation.truncate();
+    let camera_xy = camera.translation.truncate();
+    let new_xy = camera_xy.lerp(target_xy, (FOLLOW_SPEED * time.delta_secs()).min(1.0));
+
+    camera.translation.x = new_xy.x;
+    camera.translation.y = new_xy.y;
+}
@@ -0,0 +1,257 @@
+use bevy::prelude::*;
+use bevy_ecs_tilemap::tiles::TileColor;
+
+/// In-game minutes elapsed per real second, at time scale 1
+const GAME_MINUTES_PER_SECOND: f64 = 1.;
+
+/// The world wakes up at this hour of day 0
+const START_HOUR: f64 = 7.;
+
+const MINUTES_PER_HOUR: f64 = 60.;
+const MINUTES_PER_DAY: f64 = 24. * MINUTES_PER_HOUR;
+
+const MIN_TIME_SCALE: f64 = 0.25;
+const MAX_TIME_SCALE: f64 = 64.;
+
+/// Light colour over the day, as `(hour, rgb)` keyframes linearly blended
+const DAY_NIGHT_KEYFRAMES: [(f32, [f32; 3]); 8] = [
+    (0., [0.35, 0.4, 0.65]),
+    (5., [0.35, 0.4, 0.65]),
+    (7., [1.0, 0.85, 0.75]),
+    (9., [1.0, 1.0, 1.0]),
+    (17., [1.0, 1.0, 1.0]),
+    (19., [1.0, 0.75, 0.6]),
+    (21., [0.35, 0.4, 0.65]),
+    (24., [0.35, 0.4, 0.65]),
+];
+
+/// Smallest tint change worth recolouring every tile for
+const TINT_EPSILON: f32 = 0.01;
+
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GameClock>()
+            .init_resource::<DayNightTint>()
+            .add_observer(on_hour_changed)
+            .add_observer(on_day_changed)
+            .add_systems(Startup, setup_clock_label)
+            .add_systems(First, tick_game_clock)
+            .add_systems(
+                Update,
+                (
+                    control_game_clock,
+                    update_clock_label,
+                    update_day_night_tint,
+                    tint_tiles,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// In-game time, counted in minutes since midnight of day 0
+#[derive(Resource, Debug)]
+pub struct GameClock {
+    minutes: f64,
+    /// Multiplier of `GAME_MINUTES_PER_SECOND`
+    pub scale: f64,
+    pub paused: bool,
+}
+
+impl Default for GameClock {
+    fn default() -> Self {
+        Self {
+            minutes: START_HOUR * MINUTES_PER_HOUR,
+            scale: 1.,
+            paused: false,
+        }
+    }
+}
+
+impl GameClock {
+    pub fn total_minutes(&self) -> f64 {
+        self.minutes
+    }
+
+    pub fn day(&self) -> u32 {
+        (self.minutes / MINUTES_PER_DAY) as u32
+    }
+
+    pub fn hour(&self) -> u32 {
+        (self.minutes.rem_euclid(MINUTES_PER_DAY) / MINUTES_PER_HOUR) as u32
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.minutes.rem_euclid(MINUTES_PER_HOUR) as u32
+    }
+
+    /// Hour of day with the minutes as fraction, in `0..24`
+    pub fn hour_of_day(&self) -> f32 {
+        (self.minutes.rem_euclid(MINUTES_PER_DAY) / MINUTES_PER_HOUR) as f32
+    }
+}
+
+/// Fired when the clock enters a new hour
+#[derive(Event, Debug)]
+pub struct HourChanged {
+    pub day: u32,
+    pub hour: u32,
+}
+
+/// Fired when the clock enters a new day, after its `HourChanged`
+#[derive(Event, Debug)]
+pub struct DayChanged {
+    pub day: u32,
+}
+
+/// Colour multiplied into tiles and sprites for the time of day
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct DayNightTint(pub [f32; 3]);
+
+impl Default for DayNightTint {
+    fn default() -> Self {
+        Self([1.0, 1.0, 1.0])
+    }
+}
+
+impl DayNightTint {
+    fn at(hour: f32) -> Self {
+        let next = DAY_NIGHT_KEYFRAMES
+            .iter()
+            .position(|(keyframe_hour, _)| *keyframe_hour > hour)
+            .unwrap_or(DAY_NIGHT_KEYFRAMES.len() - 1)
+            .max(1);
+        let (from_hour, from) = DAY_NIGHT_KEYFRAMES[next - 1];
+        let (to_hour, to) = DAY_NIGHT_KEYFRAMES[next];
+
+        let t = ((hour - from_hour) / (to_hour - from_hour)).clamp(0., 1.);
+        Self([0, 1, 2].map(|channel| from[channel].lerp(to[channel], t)))
+    }
+
+    /// `color` lit for the time of day, alpha untouched
+    pub fn apply(&self, color: Color) -> Color {
+        let [r, g, b] = self.0;
+        let linear = color.to_linear();
+        Color::LinearRgba(LinearRgba {
+            red: linear.red * r,
+            green: linear.green * g,
+            blue: linear.blue * b,
+            alpha: linear.alpha,
+        })
+    }
+}
+
+/// Text showing the day and time
+#[derive(Component)]
+struct ClockLabel;
+
+fn tick_game_clock(mut clock: ResMut<GameClock>, time: Res<Time>, mut commands: Commands) {
+    if clock.paused {
+        return;
+    }
+
+    let previous_hours = (clock.minutes / MINUTES_PER_HOUR) as u64;
+    clock.minutes += time.delta_secs_f64() * GAME_MINUTES_PER_SECOND * clock.scale;
+    let hours = (clock.minutes / MINUTES_PER_HOUR) as u64;
+
+    // Every boundary is reported, even when a frame skips several hours
+    for hours in previous_hours + 1..=hours {
+        let day = (hours / 24) as u32;
+        let hour = (hours % 24) as u32;
+
+        commands.trigger(HourChanged { day, hour });
+        if hour == 0 {
+            commands.trigger(DayChanged { day });
+        }
+    }
+}
+
+/// P pauses, [ and ] halve and double the time scale
+fn control_game_clock(input: Res<ButtonInput<KeyCode>>, mut clock: ResMut<GameClock>) {
+    if input.just_pressed(KeyCode::KeyP) {
+        clock.paused = !clock.paused;
+        info!("game clock paused: {}", clock.paused);
+    }
+
+    let scale = if input.just_pressed(KeyCode::BracketLeft) {
+        clock.scale / 2.
+    } else if input.just_pressed(KeyCode::BracketRight) {
+        clock.scale * 2.
+    } else {
+        return;
+    };
+
+    clock.scale = scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+    info!("game clock time scale: {}", clock.scale);
+}
+
+fn on_hour_changed(hour_changed: On<HourChanged>) {
+    debug!("day {} {:02}:00", hour_changed.day, hour_changed.hour);
+}
+
+fn on_day_changed(day_changed: On<DayChanged>) {
+    info!("day {} begins", day_changed.day);
+}
+
+fn setup_clock_label(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn((
+        ClockLabel,
+        Text::new(""),
+        TextFont {
+            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+            font_size: 20.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            right: Val::Px(12.0),
+            ..default()
+        },
+    ));
+}
+
+fn update_clock_label(clock: Res<GameClock>, mut label_q: Query<&mut Text, With<ClockLabel>>) {
+    let label = format!(
+        "Day {} {:02}:{:02}{}",
+        clock.day() + 1,
+        clock.hour(),
+        clock.minute(),
+        if clock.paused {
+            " (paused)".to_string()
+        } else if clock.scale != 1. {
+            format!(" x{}", clock.scale)
+        } else {
+            String::new()
+        }
+    );
+
+    for mut text in &mut label_q {
+        if text.0 != label {
+            text.0 = label.clone();
+        }
+    }
+}
+
+fn update_day_night_tint(clock: Res<GameClock>, mut tint: ResMut<DayNightTint>) {
+    let target = DayNightTint::at(clock.hour_of_day());
+
+    let changed = (0..3).any(|channel| (target.0[channel] - tint.0[channel]).abs() > TINT_EPSILON);
+    if changed {
+        *tint = target;
+    }
+}
+
+/// Relights every tile when the tint changes, and new tiles as they spawn.
+/// Alpha is left to the roof system.
+fn tint_tiles(tint: Res<DayNightTint>, mut tiles_q: Query<&mut TileColor>) {
+    for mut tile_color in &mut tiles_q {
+        if !tint.is_changed() && !tile_color.is_added() {
+            continue;
+        }
+
+        tile_color.0 = tint.apply(Color::WHITE.with_alpha(tile_color.0.alpha()));
+    }
+}
@@ -1,13 +1,33 @@
 
-pub const GRID_WIDTH: i32 = 64;
 pub const GRID_HEIGHT: i32 = 64;
 
+/// Side of a `SpatialIndex` chunk, in tiles
+pub const CHUNK_SIZE: i32 = 32;
+
+/// Side of an `AgentIndex` hash cell, in tiles
+pub const AGENT_CELL_SIZE: i32 = 8;
+
 pub const AGENT_Z_VALUE: f32 = 20.;
 pub const PATHFINDER_Z_VALUE: f32 = 10.;
+/// Agents are y-sorted between `AGENT_Z_VALUE` and `AGENT_Z_VALUE + Y_SORT_Z_RANGE`
+pub const Y_SORT_Z_RANGE: f32 = 10.;
+/// Tall LDtk layers sit above every y-sorted entity
+pub const FOREGROUND_Z_VALUE: f32 = 40.;
+/// Speech bubbles are drawn over everything in the world
+pub const BUBBLE_Z_VALUE: f32 = 50.;
 
 pub const AGENTS_COUNT: i32 = 50;
 
+/// Agent speeds, in world units per second
+pub const WALK_SPEED: f32 = 75.;
+pub const RUN_SPEED: f32 = 120.;
+/// Agents run when their destination is further away than this, in tiles
+pub const RUN_DISTANCE: i32 = 12;
+
 pub const PATHFINDER_MAX_DEPTH: usize = 100;
 
+/// How far agents can see, in tiles
+pub const SIGHT_RADIUS: i32 = 8;
+
 /// Each grid tile is 16×16 world units
 pub const TILE_SIZE: f32 = 16.0;
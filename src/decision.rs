@@ -0,0 +1,818 @@
+use std::{collections::HashMap, mem::discriminant};
+
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::{
+    agent::{Agent, SelectedAgent, Walking},
+    animation::AnimationController,
+    clock::GameClock,
+    constants::*,
+    schedule::{Places, Schedule, SchedulePaused},
+    visibility::VisibleTiles,
+    world::{agent_idx::AgentIndex, components::*, grid::CursorTile},
+};
+
+/// Real seconds between two decisions of an agent, unless its goal is over
+const DECISION_INTERVAL_SECONDS: f32 = 1.;
+
+/// Bonus kept by the current goal so agents don't flip between close scores
+const CURRENT_GOAL_BONUS: f32 = 0.1;
+
+/// Goal durations and cooldowns, in game minutes
+const WAIT_MINUTES: f64 = 15.;
+const TALK_MINUTES: f64 = 10.;
+const TALK_COOLDOWN_MINUTES: f64 = 120.;
+
+/// Chance per decision to start talking to an available agent nearby
+const TALK_CHANCE: f32 = 0.1;
+const TALK_RADIUS: i32 = 3;
+
+const FOLLOW_DISTANCE: i32 = 2;
+const FLEE_DISTANCE: i32 = 10;
+
+pub struct DecisionPlugin;
+
+impl Plugin for DecisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DecisionLayer>()
+            .add_observer(on_add_agent)
+            .add_observer(on_talk_intent)
+            .add_systems(
+                Update,
+                (toggle_debug_intents, decide_goals, pursue_goals).chain(),
+            );
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Goal {
+    Wander,
+    /// Walk to a point of interest
+    GoTo(GridPosition),
+    Follow(Entity),
+    Flee(Entity),
+    /// Walk up to the agent, then talk until `until`
+    TalkTo {
+        target: Entity,
+        until: Option<f64>,
+    },
+    Wait {
+        until: f64,
+    },
+    /// Leave the agent to its `Schedule`
+    FollowSchedule,
+}
+
+impl Goal {
+    fn same_kind(&self, other: &Goal) -> bool {
+        discriminant(self) == discriminant(other)
+    }
+
+    /// Goals other agents shouldn't interrupt
+    fn is_busy(&self) -> bool {
+        matches!(self, Goal::TalkTo { .. } | Goal::Flee(_))
+    }
+}
+
+/// Goal an agent is pursuing, chosen by the `DecisionLayer`
+#[derive(Component, Debug)]
+pub struct Brain {
+    pub goal: Option<Goal>,
+    timer: Timer,
+    last_talk: Option<f64>,
+}
+
+impl Default for Brain {
+    fn default() -> Self {
+        Self {
+            goal: None,
+            timer: Timer::from_seconds(DECISION_INTERVAL_SECONDS, TimerMode::Repeating),
+            last_talk: None,
+        }
+    }
+}
+
+/// Agents seeing this entity flee from it
+#[derive(Component)]
+pub struct Threat;
+
+/// Agent to follow around
+#[derive(Component)]
+pub struct FollowTarget(pub Entity);
+
+/// Agent held by something outside the decision layer, like a conversation
+/// with the player. Its brain is left alone and other agents don't approach it.
+#[derive(Component)]
+pub struct Engaged;
+
+/// Fired when an agent starts talking to another one
+#[derive(Event, Debug)]
+pub struct TalkIntent {
+    pub speaker: Entity,
+    pub listener: Entity,
+}
+
+#[derive(Debug, Clone)]
+pub struct NearbyAgent {
+    pub entity: Entity,
+    pub position: GridPosition,
+    pub visible: bool,
+    pub threat: bool,
+    /// Neither busy nor walking
+    pub available: bool,
+}
+
+/// What an agent knows when choosing a goal
+#[derive(Debug)]
+pub struct DecisionContext<'a> {
+    pub entity: Entity,
+    pub position: GridPosition,
+    pub goal: Option<&'a Goal>,
+    /// Game minutes
+    pub now: f64,
+    pub walking: bool,
+    pub has_schedule: bool,
+    /// `FollowTarget`, when nearby
+    pub leader: Option<Entity>,
+    /// Agents within `SIGHT_RADIUS`, closest first
+    pub nearby: Vec<NearbyAgent>,
+    pub points_of_interest: &'a [GridPosition],
+    pub last_talk: Option<f64>,
+    /// Uniform random number in `0..1`, drawn once per decision
+    pub roll: f32,
+}
+
+impl DecisionContext<'_> {
+    /// Whether the current goal is reached, over or no longer possible
+    fn goal_done(&self) -> bool {
+        let Some(goal) = self.goal else {
+            return true;
+        };
+
+        let is_nearby = |target: &Entity| self.nearby.iter().any(|a| a.entity == *target);
+
+        match goal {
+            Goal::Wander | Goal::GoTo(_) => !self.walking,
+            Goal::Wait { until } => self.now >= *until,
+            Goal::TalkTo {
+                until: Some(until), ..
+            } => self.now >= *until,
+            Goal::TalkTo { target, .. } => !is_nearby(target),
+            Goal::Follow(target) => self.leader != Some(*target),
+            Goal::Flee(threat) => !self
+                .nearby
+                .iter()
+                .any(|a| a.entity == *threat && a.threat && a.visible),
+            Goal::FollowSchedule => !self.has_schedule,
+        }
+    }
+}
+
+/// Proposes a goal with its score, higher wins
+pub type Consideration = fn(&DecisionContext) -> Option<(Goal, f32)>;
+
+/// Utility AI scoring every consideration for each agent. Plugins can push
+/// their own considerations.
+#[derive(Resource)]
+pub struct DecisionLayer {
+    pub considerations: Vec<(&'static str, Consideration)>,
+}
+
+impl Default for DecisionLayer {
+    fn default() -> Self {
+        Self {
+            considerations: vec![
+                ("flee", consider_flee),
+                ("follow", consider_follow),
+                ("talk", consider_talk),
+                ("schedule", consider_schedule),
+                ("point of interest", consider_point_of_interest),
+                ("wander", consider_wander),
+                ("wait", consider_wait),
+            ],
+        }
+    }
+}
+
+impl DecisionLayer {
+    pub fn choose(&self, ctx: &DecisionContext) -> Option<Goal> {
+        self.considerations
+            .iter()
+            .filter_map(|(name, consider)| {
+                let (goal, mut score) = consider(ctx)?;
+                if ctx.goal.is_some_and(|current| current.same_kind(&goal)) {
+                    score += CURRENT_GOAL_BONUS;
+                }
+                Some((name, goal, score))
+            })
+            .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+            .map(|(name, goal, score)| {
+                trace!("{:?}: {} ({:.2})", ctx.entity, name, score);
+                goal
+            })
+    }
+}
+
+fn consider_flee(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    let threat = ctx.nearby.iter().find(|a| a.threat && a.visible)?;
+    let distance = ctx.position.distance(&threat.position);
+
+    Some((
+        Goal::Flee(threat.entity),
+        2.0 - distance / SIGHT_RADIUS as f32,
+    ))
+}
+
+fn consider_follow(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    let leader = ctx.leader?;
+    Some((Goal::Follow(leader), 0.8))
+}
+
+fn consider_talk(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    if let Some(goal @ Goal::TalkTo { .. }) = ctx.goal {
+        return Some((goal.clone(), 0.7));
+    }
+
+    let rested = ctx
+        .last_talk
+        .is_none_or(|last| ctx.now - last >= TALK_COOLDOWN_MINUTES);
+
+    if ctx.walking || !rested || ctx.roll >= TALK_CHANCE {
+        return None;
+    }
+
+    let partner = ctx.nearby.iter().find(|a| {
+        a.available && a.visible && ctx.position.distance(&a.position) <= TALK_RADIUS as f32
+    })?;
+
+    Some((
+        Goal::TalkTo {
+            target: partner.entity,
+            until: None,
+        },
+        0.7,
+    ))
+}
+
+fn consider_schedule(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    ctx.has_schedule.then_some((Goal::FollowSchedule, 0.5))
+}
+
+fn consider_point_of_interest(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    if ctx.walking || ctx.has_schedule || ctx.points_of_interest.is_empty() {
+        return None;
+    }
+
+    let idx = (ctx.roll * ctx.points_of_interest.len() as f32) as usize;
+    let poi = ctx.points_of_interest.get(idx)?;
+
+    Some((Goal::GoTo(poi.clone()), 0.2 + 0.2 * ctx.roll))
+}
+
+fn consider_wander(_ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    Some((Goal::Wander, 0.3))
+}
+
+fn consider_wait(ctx: &DecisionContext) -> Option<(Goal, f32)> {
+    if ctx.walking {
+        return None;
+    }
+
+    Some((
+        Goal::Wait {
+            until: ctx.now + WAIT_MINUTES,
+        },
+        0.4 * ctx.roll,
+    ))
+}
+
+fn on_add_agent(add: On<Add, Agent>, mut commands: Commands) {
+    commands.entity(add.entity).insert(Brain::default());
+}
+
+/// The listener stops and talks back, unless busy
+fn on_talk_intent(
+    talk: On<TalkIntent>,
+    mut brains_q: Query<(&mut Brain, &mut AnimationController, Has<Schedule>), Without<Engaged>>,
+    clock: Res<GameClock>,
+    mut commands: Commands,
+) {
+    let Ok((mut brain, mut controller, has_schedule)) = brains_q.get_mut(talk.listener) else {
+        return;
+    };
+
+    if brain.goal.as_ref().is_some_and(Goal::is_busy) {
+        return;
+    }
+
+    let now = clock.total_minutes();
+    brain.goal = Some(Goal::TalkTo {
+        target: talk.speaker,
+        until: Some(now + TALK_MINUTES),
+    });
+    brain.last_talk = Some(now);
+    controller.play("talk");
+
+    if has_schedule {
+        commands.entity(talk.listener).insert(SchedulePaused);
+    }
+}
+
+type DecidingAgent = (
+    Entity,
+    &'static GridPosition,
+    &'static mut Brain,
+    &'static VisibleTiles,
+    Option<&'static FollowTarget>,
+    Has<Walking>,
+    Has<Schedule>,
+    Has<Threat>,
+);
+
+/// Chooses a new goal for agents whose goal is over, and re-scores the others
+/// every `DECISION_INTERVAL_SECONDS`
+fn decide_goals(
+    mut agents_q: Query<DecidingAgent, Without<Engaged>>,
+    layer: Res<DecisionLayer>,
+    agent_idx: Res<AgentIndex>,
+    clock: Res<GameClock>,
+    time: Res<Time>,
+    places: Places,
+    mut commands: Commands,
+) {
+    let now = clock.total_minutes();
+    let mut rng = rand::thread_rng();
+
+    // What agents can tell about each other: (threat, available)
+    let snapshot: HashMap<Entity, (bool, bool)> = agents_q
+        .iter()
+        .map(|(entity, _, brain, _, _, walking, _, threat)| {
+            let busy = brain.goal.as_ref().is_some_and(Goal::is_busy);
+            (entity, (threat, !busy && !walking))
+        })
+        .collect();
+
+    let points_of_interest: Vec<GridPosition> = places
+        .rooms_q
+        .iter()
+        .filter(|room| room.name.is_some())
+        .filter_map(|room| {
+            let (x, y) = room.tiles[room.tiles.len() / 2];
+            places
+                .free_tile_near((x, y), 2)
+                .map(|(x, y)| GridPosition { x, y })
+        })
+        .collect();
+
+    for (entity, position, mut brain, visible, follow, walking, has_schedule, _) in &mut agents_q {
+        brain.timer.tick(time.delta());
+
+        let mut nearby: Vec<NearbyAgent> = agent_idx
+            .agents_in_radius(position, SIGHT_RADIUS)
+            .filter(|(other, _)| *other != entity)
+            .filter_map(|(other, other_position)| {
+                let (threat, available) = snapshot.get(&other)?;
+                Some(NearbyAgent {
+                    entity: other,
+                    position: other_position.clone(),
+                    visible: visible
+                        .tiles
+                        .contains(&(other_position.x, other_position.y)),
+                    threat: *threat,
+                    available: *available,
+                })
+            })
+            .collect();
+        nearby.sort_by(|a, b| {
+            position
+                .distance(&a.position)
+                .total_cmp(&position.distance(&b.position))
+        });
+
+        let leader = follow
+            .map(|FollowTarget(leader)| *leader)
+            .filter(|leader| nearby.iter().any(|a| a.entity == *leader));
+
+        let ctx = DecisionContext {
+            entity,
+            position: position.clone(),
+            goal: brain.goal.as_ref(),
+            now,
+            walking,
+            has_schedule,
+            leader,
+            nearby,
+            points_of_interest: &points_of_interest,
+            last_talk: brain.last_talk,
+            roll: rng.r#gen(),
+        };
+
+        let done = ctx.goal_done();
+        if !done && !brain.timer.just_finished() {
+            continue;
+        }
+
+        let Some(goal) = layer.choose(&ctx) else {
+            continue;
+        };
+
+        if !done && ctx.goal.is_some_and(|current| current.same_kind(&goal)) {
+            continue;
+        }
+
+        debug!("{:?} now pursues {:?}", entity, goal);
+
+        // Intents
+        let mut agent_commands = commands.entity(entity);
+        match &goal {
+            Goal::FollowSchedule => {
+                agent_commands.remove::<SchedulePaused>();
+            }
+            _ if has_schedule => {
+                agent_commands.insert(SchedulePaused);
+            }
+            _ => {}
+        }
+
+        match &goal {
+            Goal::Wander => {
+                if let Some((x, y)) = places.anywhere() {
+                    agent_commands.insert(Walking {
+                        destination: GridPosition { x, y },
+                    });
+                }
+            }
+            Goal::GoTo(destination) => {
+                agent_commands.insert(Walking {
+                    destination: destination.clone(),
+                });
+            }
+            _ => {}
+        }
+
+        brain.goal = Some(goal);
+    }
+}
+
+type PursuingAgent = (
+    Entity,
+    &'static GridPosition,
+    &'static mut Brain,
+    &'static mut AnimationController,
+    Option<&'static Walking>,
+);
+
+/// Moves agents towards the agents their goal is about, and starts conversations
+fn pursue_goals(
+    mut agents_q: Query<PursuingAgent, Without<Engaged>>,
+    positions_q: Query<&GridPosition, With<Agent>>,
+    clock: Res<GameClock>,
+    places: Places,
+    mut commands: Commands,
+) {
+    let now = clock.total_minutes();
+
+    for (entity, position, mut brain, mut controller, walking) in &mut agents_q {
+        let walk_to = |tile: Option<(i32, i32)>, commands: &mut Commands| {
+            if let Some((x, y)) = tile {
+                commands.entity(entity).insert(Walking {
+                    destination: GridPosition { x, y },
+                });
+            }
+        };
+
+        match brain.goal.clone() {
+            Some(Goal::Follow(leader)) => {
+                let Ok(leader_position) = positions_q.get(leader) else {
+                    continue;
+                };
+
+                let lagging = position.chebyshev(leader_position) > FOLLOW_DISTANCE;
+                let heading_elsewhere = walking.is_some_and(|walking| {
+                    walking.destination.chebyshev(leader_position) > FOLLOW_DISTANCE
+                });
+
+                if (lagging && walking.is_none()) || heading_elsewhere {
+                    let near_leader = (leader_position.x, leader_position.y);
+                    walk_to(
+                        places.free_tile_near(near_leader, FOLLOW_DISTANCE),
+                        &mut commands,
+                    );
+                }
+            }
+            Some(Goal::Flee(threat)) => {
+                let Ok(threat_position) = positions_q.get(threat) else {
+                    continue;
+                };
+
+                if walking.is_none() {
+                    let away = (Vec2::new(position.x as f32, position.y as f32)
+                        - Vec2::new(threat_position.x as f32, threat_position.y as f32))
+                    .normalize_or(Vec2::X);
+
+                    // Closer targets when the far one is past the edge of the loaded levels
+                    let target = (1..=FLEE_DISTANCE).rev().find_map(|distance| {
+                        let away = away * distance as f32;
+                        places.free_tile_near(
+                            (position.x + away.x as i32, position.y + away.y as i32),
+                            3,
+                        )
+                    });
+                    walk_to(target, &mut commands);
+                }
+            }
+            Some(Goal::TalkTo {
+                target,
+                until: None,
+            }) => {
+                let Ok(target_position) = positions_q.get(target) else {
+                    continue;
+                };
+
+                if walking.is_some() {
+                    continue;
+                }
+
+                if position.chebyshev(target_position) <= 1 {
+                    brain.goal = Some(Goal::TalkTo {
+                        target,
+                        until: Some(now + TALK_MINUTES),
+                    });
+                    brain.last_talk = Some(now);
+                    controller.play("talk");
+
+                    commands.trigger(TalkIntent {
+                        speaker: entity,
+                        listener: target,
+                    });
+                } else {
+                    let near_target = (target_position.x, target_position.y);
+                    walk_to(places.free_tile_near(near_target, 1), &mut commands);
+                }
+            }
+            Some(Goal::Wait { .. }) if walking.is_none() => controller.play("idle"),
+            _ => {}
+        }
+    }
+}
+
+/// T marks the selected agent as a threat, L makes the agent under the cursor
+/// follow the selected agent. Pressing again undoes it.
+fn toggle_debug_intents(
+    input: Res<ButtonInput<KeyCode>>,
+    selected: Res<SelectedAgent>,
+    cursor_tile: Res<CursorTile>,
+    agent_idx: Res<AgentIndex>,
+    agents_q: Query<(Has<Threat>, Option<&FollowTarget>), With<Agent>>,
+    mut commands: Commands,
+) {
+    let Some(selected) = selected.0 else {
+        return;
+    };
+
+    if input.just_pressed(KeyCode::KeyT)
+        && let Ok((is_threat, _)) = agents_q.get(selected)
+    {
+        if is_threat {
+            commands.entity(selected).remove::<Threat>();
+        } else {
+            commands.entity(selected).insert(Threat);
+        }
+    }
+
+    if input.just_pressed(KeyCode::KeyL)
+        && let Some((follower, _)) = cursor_tile
+            .0
+            .as_ref()
+            .and_then(|tile| agent_idx.nearest_agent(tile, 1, Some(selected)))
+        && let Ok((_, follow)) = agents_q.get(follower)
+    {
+        if follow.is_some_and(|FollowTarget(leader)| *leader == selected) {
+            commands.entity(follower).remove::<FollowTarget>();
+        } else {
+            commands.entity(follower).insert(FollowTarget(selected));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{
+        rooms::RoomIndex,
+        spatial_idx::{SpatialIndex, TileData},
+    };
+
+    fn context(goal: Option<&Goal>) -> DecisionContext<'_> {
+        DecisionContext {
+            entity: Entity::from_raw_u32(1).unwrap(),
+            position: GridPosition { x: 0, y: 0 },
+            goal,
+            now: 0.,
+            walking: false,
+            has_schedule: false,
+            leader: None,
+            nearby: vec![],
+            points_of_interest: &[],
+            last_talk: None,
+            roll: 0.5,
+        }
+    }
+
+    fn nearby(entity: Entity, x: i32, threat: bool, visible: bool) -> NearbyAgent {
+        NearbyAgent {
+            entity,
+            position: GridPosition { x, y: 0 },
+            visible,
+            threat,
+            available: true,
+        }
+    }
+
+    fn other() -> Entity {
+        Entity::from_raw_u32(2).unwrap()
+    }
+
+    #[test]
+    fn visible_threat_wins_over_everything() {
+        let mut ctx = context(None);
+        ctx.has_schedule = true;
+        ctx.leader = Some(other());
+        ctx.nearby = vec![nearby(other(), 3, true, true)];
+
+        assert_eq!(
+            DecisionLayer::default().choose(&ctx),
+            Some(Goal::Flee(other()))
+        );
+    }
+
+    #[test]
+    fn hidden_threat_is_ignored() {
+        let mut ctx = context(None);
+        ctx.has_schedule = true;
+        ctx.nearby = vec![nearby(other(), 3, true, false)];
+
+        assert_eq!(
+            DecisionLayer::default().choose(&ctx),
+            Some(Goal::FollowSchedule)
+        );
+    }
+
+    #[test]
+    fn following_wins_over_talking() {
+        let mut ctx = context(None);
+        ctx.leader = Some(other());
+        ctx.nearby = vec![nearby(other(), 1, false, true)];
+        ctx.roll = 0.;
+
+        assert_eq!(
+            DecisionLayer::default().choose(&ctx),
+            Some(Goal::Follow(other()))
+        );
+    }
+
+    #[test]
+    fn talks_to_an_available_neighbour() {
+        let mut ctx = context(None);
+        ctx.has_schedule = true;
+        ctx.nearby = vec![nearby(other(), 2, false, true)];
+        ctx.roll = 0.;
+
+        assert_eq!(
+            DecisionLayer::default().choose(&ctx),
+            Some(Goal::TalkTo {
+                target: other(),
+                until: None
+            })
+        );
+    }
+
+    #[test]
+    fn current_goal_wins_ties() {
+        let layer = DecisionLayer {
+            considerations: vec![
+                ("wander", |_| Some((Goal::Wander, 0.5))),
+                ("schedule", |_| Some((Goal::FollowSchedule, 0.5))),
+            ],
+        };
+
+        assert_eq!(
+            layer.choose(&context(Some(&Goal::Wander))),
+            Some(Goal::Wander)
+        );
+        assert_eq!(
+            layer.choose(&context(Some(&Goal::FollowSchedule))),
+            Some(Goal::FollowSchedule)
+        );
+    }
+
+    #[test]
+    fn no_goal_without_candidates() {
+        let empty = DecisionLayer {
+            considerations: vec![],
+        };
+        assert_eq!(empty.choose(&context(None)), None);
+
+        // Walking agents neither talk nor wait
+        let mut ctx = context(None);
+        ctx.walking = true;
+        let layer = DecisionLayer {
+            considerations: vec![("talk", consider_talk), ("wait", consider_wait)],
+        };
+        assert_eq!(layer.choose(&ctx), None);
+    }
+
+    fn app(considerations: Vec<(&'static str, Consideration)>) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, DecisionPlugin))
+            .insert_resource(DecisionLayer { considerations })
+            .init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<SelectedAgent>()
+            .init_resource::<CursorTile>()
+            .init_resource::<AgentIndex>()
+            .init_resource::<GameClock>()
+            .init_resource::<RoomIndex>()
+            .init_resource::<SpatialIndex>();
+
+        // A single free tile to wander to
+        let tile = app.world_mut().spawn(Tile { x: 5, y: 0 }).id();
+        app.world_mut()
+            .resource_mut::<SpatialIndex>()
+            .get_or_insert_with(5, 0, || TileData {
+                entity: tile,
+                flags: TileFlags::TRAVERSABLE_TERRAIN,
+                tilemap_entity: None,
+                tile_entity: None,
+            });
+
+        app
+    }
+
+    fn spawn_agent(app: &mut App) -> Entity {
+        let position = GridPosition { x: 0, y: 0 };
+        let agent = app
+            .world_mut()
+            .spawn((
+                position.clone(),
+                Brain::default(),
+                VisibleTiles::default(),
+                AnimationController::new("character", "idle"),
+            ))
+            .id();
+        app.world_mut()
+            .resource_mut::<AgentIndex>()
+            .insert(agent, position);
+        agent
+    }
+
+    #[test]
+    fn wandering_agent_starts_walking() {
+        let mut app = app(vec![("wander", consider_wander)]);
+        let agent = spawn_agent(&mut app);
+
+        app.update();
+
+        let world = app.world();
+        assert_eq!(world.get::<Brain>(agent).unwrap().goal, Some(Goal::Wander));
+        assert_eq!(
+            world.get::<Walking>(agent).unwrap().destination,
+            GridPosition { x: 5, y: 0 }
+        );
+    }
+
+    #[test]
+    fn waiting_agent_stays_put() {
+        let mut app = app(vec![("wait", |ctx| {
+            Some((
+                Goal::Wait {
+                    until: ctx.now + 1.,
+                },
+                1.,
+            ))
+        })]);
+        let agent = spawn_agent(&mut app);
+
+        app.update();
+
+        let world = app.world();
+        assert!(matches!(
+            world.get::<Brain>(agent).unwrap().goal,
+            Some(Goal::Wait { .. })
+        ));
+        assert!(world.get::<Walking>(agent).is_none());
+    }
+
+    #[test]
+    fn engaged_agent_is_left_alone() {
+        let mut app = app(vec![("wander", consider_wander)]);
+        let agent = spawn_agent(&mut app);
+        app.world_mut().entity_mut(agent).insert(Engaged);
+
+        app.update();
+
+        let world = app.world();
+        assert_eq!(world.get::<Brain>(agent).unwrap().goal, None);
+        assert!(world.get::<Walking>(agent).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+
+use crate::{constants::*, world::LevelOrigins};
+
+pub struct DepthPlugin;
+
+impl Plugin for DepthPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TallLayers>()
+            .add_systems(Update, move_tall_layers_to_foreground)
+            .add_systems(
+                PostUpdate,
+                y_sort_system.before(TransformSystems::Propagate),
+            );
+    }
+}
+
+/// Sets the z of the entity from its world y: lower on screen is drawn on top.
+/// `base` is the z at the top edge of the spawned levels.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct YSort {
+    pub base: f32,
+}
+
+/// LDtk layers drawn above every y-sorted entity. Tall objects (trees, building
+/// fronts) are split in LDtk into a background layer with their base and one of
+/// these layers with the part characters walk behind, like the roofs of
+/// `Building_Foreground`.
+#[derive(Resource, Debug)]
+pub struct TallLayers {
+    pub identifiers: Vec<String>,
+    pub suffix: String,
+}
+
+impl Default for TallLayers {
+    fn default() -> Self {
+        Self {
+            identifiers: vec![],
+            suffix: "_Foreground".to_string(),
+        }
+    }
+}
+
+impl TallLayers {
+    pub fn is_tall(&self, identifier: &str) -> bool {
+        identifier.ends_with(&self.suffix) || self.identifiers.iter().any(|i| i == identifier)
+    }
+}
+
+fn y_sort_system(mut query: Query<(&mut Transform, &YSort)>, level_origins: Res<LevelOrigins>) {
+    let Some((min_row, max_row)) = level_origins.rows() else {
+        return;
+    };
+    let bottom = min_row as f32 * TILE_SIZE;
+    let height = (max_row - min_row + 1) as f32 * TILE_SIZE;
+
+    for (mut transform, y_sort) in &mut query {
+        // Every entity moves in the range when a level is spawned or despawned
+        if !transform.is_changed() && !level_origins.is_changed() {
+            continue;
+        }
+
+        let depth = (1.0 - (transform.translation.y - bottom) / height).clamp(0.0, 1.0);
+        let z = y_sort.base + depth * Y_SORT_Z_RANGE;
+
+        if transform.translation.z != z {
+            transform.translation.z = z;
+        }
+    }
+}
+
+fn move_tall_layers_to_foreground(
+    mut layers_q: Query<(&LayerMetadata, &mut Transform), Added<LayerMetadata>>,
+    tall_layers: Res<TallLayers>,
+) {
+    for (layer, mut transform) in &mut layers_q {
+        if tall_layers.is_tall(&layer.identifier) {
+            transform.translation.z = FOREGROUND_Z_VALUE;
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use bevy::{
+    input::{
+        ButtonState, InputSystems,
+        keyboard::{Key, KeyboardInput},
+        mouse::{MouseScrollUnit, MouseWheel},
+    },
+    prelude::*,
+    ui::UiSystems,
+};
+
+use crate::{
+    agent::{SelectedAgent, SelectedAgentClicked, Walking},
+    background::{
+        ConversationId, DialogueCancelled, DialogueFailed, DialogueQueued, DialogueRequest,
+        LlmService, RequestId,
+    },
+    decision::{Brain, Engaged},
+    message_animation::{MessageCollection, MessageCompleted},
+    persona::NpcPersona,
+    schedule::{Schedule, SchedulePaused},
+};
+
+const PANEL_BACKGROUND: Color = Color::srgba(0.08, 0.07, 0.06, 0.9);
+const PLAYER_COLOR: Color = Color::srgb(0.7, 0.85, 1.0);
+const FONT: &str = "fonts/FiraSans-Bold.ttf";
+const FONT_SIZE: f32 = 16.;
+
+/// Longest line the player can type
+const MAX_INPUT_CHARS: usize = 200;
+
+/// Logical pixels scrolled per wheel line
+const SCROLL_LINE_HEIGHT: f32 = 20.;
+
+pub struct DialoguePlugin;
+
+impl Plugin for DialoguePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DialoguePanel>()
+            .add_observer(open_dialogue_on_click)
+            .add_observer(on_dialogue_queued)
+            .add_observer(on_message_completed)
+            .add_observer(on_dialogue_failed)
+            .add_observer(on_dialogue_cancelled)
+            .add_systems(Startup, setup_dialogue_panel)
+            .add_systems(
+                PreUpdate,
+                capture_input.after(InputSystems).after(UiSystems::Focus),
+            )
+            .add_systems(
+                Update,
+                (
+                    close_dialogue_on_deselect,
+                    type_dialogue,
+                    follow_pending_reply,
+                    update_dialogue_panel,
+                    scroll_dialogue_history,
+                )
+                    .chain(),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speaker {
+    Player,
+    Npc,
+}
+
+#[derive(Debug, Clone)]
+pub struct DialogueLine {
+    pub speaker: Speaker,
+    pub text: String,
+}
+
+/// What the player and an NPC said to each other this session
+#[derive(Component, Default, Debug)]
+pub struct DialogueLog(pub Vec<DialogueLine>);
+
+/// Conversation shown in the panel, if any
+#[derive(Resource, Default)]
+pub struct DialoguePanel {
+    pub npc: Option<Entity>,
+    input: String,
+    /// The player's line was sent, its request ID comes with `DialogueQueued`
+    requested: bool,
+    /// Reply being streamed, the last line of the log
+    pending: Option<RequestId>,
+    /// Keep the history scrolled to the last line
+    follow_bottom: bool,
+}
+
+impl DialoguePanel {
+    fn is_answering(&self) -> bool {
+        self.requested || self.pending.is_some()
+    }
+}
+
+#[derive(Component)]
+struct DialoguePanelRoot;
+
+#[derive(Component)]
+struct DialogueTitle;
+
+#[derive(Component)]
+struct DialogueHistory;
+
+#[derive(Component)]
+struct DialogueHistoryText;
+
+#[derive(Component)]
+struct DialogueInput;
+
+fn setup_dialogue_panel(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = TextFont {
+        font: asset_server.load(FONT),
+        font_size: FONT_SIZE,
+        ..default()
+    };
+
+    commands.spawn((
+        DialoguePanelRoot,
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(12.0),
+            left: Val::Percent(20.0),
+            width: Val::Percent(60.0),
+            height: Val::Percent(35.0),
+            flex_direction: FlexDirection::Column,
+            padding: UiRect::all(Val::Px(10.0)),
+            row_gap: Val::Px(6.0),
+            ..default()
+        },
+        BackgroundColor(PANEL_BACKGROUND),
+        // Tracks hovering, so clicks on the panel don't select agents
+        Interaction::default(),
+        Visibility::Hidden,
+        children![
+            (DialogueTitle, Text::new(""), font.clone()),
+            (
+                DialogueHistory,
+                Node {
+                    flex_grow: 1.0,
+                    overflow: Overflow::scroll_y(),
+                    ..default()
+                },
+                ScrollPosition::default(),
+                children![(DialogueHistoryText, Text::new(""), font.clone())],
+            ),
+            (DialogueInput, Text::new(""), font, TextColor(PLAYER_COLOR)),
+        ],
+    ));
+}
+
+/// Keys the panel takes as text or commands
+fn is_typed(key: &KeyboardInput) -> bool {
+    key.text.is_some()
+        || matches!(
+            key.logical_key,
+            Key::Character(_) | Key::Space | Key::Backspace | Key::Enter | Key::Escape
+        )
+}
+
+/// Keys typed into the panel don't reach the rest of the game, nor clicks on it
+fn capture_input(
+    mut keyboard: MessageReader<KeyboardInput>,
+    panel: Res<DialoguePanel>,
+    mut keys: ResMut<ButtonInput<KeyCode>>,
+    mut mouse: ResMut<ButtonInput<MouseButton>>,
+    root_q: Query<&Interaction, With<DialoguePanelRoot>>,
+) {
+    if panel.npc.is_none() {
+        keyboard.clear();
+        return;
+    }
+
+    for key in keyboard.read().filter(|key| is_typed(key)) {
+        keys.reset(key.key_code);
+    }
+
+    if root_q
+        .iter()
+        .any(|interaction| *interaction != Interaction::None)
+    {
+        mouse.reset_all();
+    }
+}
+
+/// Name of the NPC in the panel
+fn npc_name(persona: Option<&NpcPersona>) -> String {
+    persona.map_or("Stranger".to_string(), |persona| persona.name.clone())
+}
+
+type DialogueNpc = (
+    &'static mut Brain,
+    Option<&'static mut DialogueLog>,
+    Has<Schedule>,
+);
+
+/// The NPC gets back to its routine and decides what to do next
+fn release_npc(npc: Entity, npcs_q: &mut Query<DialogueNpc>, commands: &mut Commands) {
+    let Ok((mut brain, _, has_schedule)) = npcs_q.get_mut(npc) else {
+        return;
+    };
+
+    brain.goal = None;
+    let mut npc_commands = commands.entity(npc);
+    npc_commands.remove::<Engaged>();
+    if has_schedule {
+        npc_commands.remove::<SchedulePaused>();
+    }
+}
+
+fn close_dialogue(
+    panel: &mut DialoguePanel,
+    npcs_q: &mut Query<DialogueNpc>,
+    llm: &mut LlmService,
+    commands: &mut Commands,
+) {
+    if let Some(request) = panel.pending.take()
+        && let Some(npc) = llm.cancel(request)
+    {
+        commands.trigger(DialogueCancelled { request, npc });
+    }
+
+    if let Some(npc) = panel.npc.take() {
+        release_npc(npc, npcs_q, commands);
+    }
+    panel.requested = false;
+    panel.input.clear();
+}
+
+/// Clicking the selected agent again opens a conversation with it
+fn open_dialogue_on_click(
+    clicked: On<SelectedAgentClicked>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<DialogueNpc>,
+    mut llm: ResMut<LlmService>,
+    mut commands: Commands,
+) {
+    let npc = clicked.entity;
+    if panel.npc == Some(npc) || !npcs_q.contains(npc) {
+        return;
+    }
+
+    close_dialogue(&mut panel, &mut npcs_q, &mut llm, &mut commands);
+
+    // The NPC stops and listens while the panel is open
+    if let Ok((_, log, has_schedule)) = npcs_q.get(npc) {
+        let mut npc_commands = commands.entity(npc);
+        npc_commands.insert(Engaged).remove::<Walking>();
+        if log.is_none() {
+            npc_commands.insert(DialogueLog::default());
+        }
+        if has_schedule {
+            npc_commands.insert(SchedulePaused);
+        }
+    }
+
+    panel.npc = Some(npc);
+    panel.follow_bottom = true;
+}
+
+/// Selecting another agent or the ground leaves the conversation
+fn close_dialogue_on_deselect(
+    selected: Res<SelectedAgent>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<DialogueNpc>,
+    mut llm: ResMut<LlmService>,
+    mut commands: Commands,
+) {
+    let Some(npc) = panel.npc else {
+        return;
+    };
+
+    // The reply of a despawned NPC is dropped like when the panel closes
+    if !npcs_q.contains(npc) || (selected.is_changed() && selected.0 != Some(npc)) {
+        close_dialogue(&mut panel, &mut npcs_q, &mut llm, &mut commands);
+    }
+}
+
+/// Edits the input line. Enter sends it, Escape closes the panel.
+fn type_dialogue(
+    mut keyboard: MessageReader<KeyboardInput>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<DialogueNpc>,
+    conversations_q: Query<&ConversationId>,
+    mut llm: ResMut<LlmService>,
+    mut selected: ResMut<SelectedAgent>,
+    mut commands: Commands,
+) {
+    let Some(npc) = panel.npc else {
+        keyboard.clear();
+        return;
+    };
+
+    for key in keyboard.read() {
+        if key.state != ButtonState::Pressed {
+            continue;
+        }
+
+        match &key.logical_key {
+            Key::Escape => {
+                close_dialogue(&mut panel, &mut npcs_q, &mut llm, &mut commands);
+                selected.0 = None;
+                return;
+            }
+            Key::Backspace => {
+                panel.input.pop();
+            }
+            Key::Enter => {
+                let prompt = panel.input.trim().to_string();
+                if prompt.is_empty() || panel.is_answering() {
+                    continue;
+                }
+
+                let (Ok(ConversationId(conversation_id)), Ok((_, Some(mut log), _))) =
+                    (conversations_q.get(npc), npcs_q.get_mut(npc))
+                else {
+                    continue;
+                };
+
+                log.0.push(DialogueLine {
+                    speaker: Speaker::Player,
+                    text: prompt.clone(),
+                });
+                log.0.push(DialogueLine {
+                    speaker: Speaker::Npc,
+                    text: String::new(),
+                });
+
+                commands.trigger(DialogueRequest {
+                    npc,
+                    prompt,
+                    conversation_id: conversation_id.clone(),
+                });
+                panel.requested = true;
+                panel.input.clear();
+                panel.follow_bottom = true;
+            }
+            _ => {
+                let Some(text) = &key.text else {
+                    continue;
+                };
+
+                for c in text.chars().filter(|c| !c.is_control()) {
+                    if panel.input.chars().count() < MAX_INPUT_CHARS {
+                        panel.input.push(c);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Mirrors the typewriter of the reply's speech bubble into the log
+fn follow_pending_reply(
+    panel: Res<DialoguePanel>,
+    message_collection: Res<MessageCollection>,
+    mut npcs_q: Query<DialogueNpc>,
+) {
+    let (Some(npc), Some(request)) = (panel.npc, panel.pending) else {
+        return;
+    };
+
+    let Some(message) = message_collection.0.get(&request) else {
+        return;
+    };
+
+    if let Ok((_, Some(mut log), _)) = npcs_q.get_mut(npc)
+        && let Some(line) = log.0.last_mut()
+    {
+        let shown = message.shown_text();
+        if line.text != shown {
+            line.text = shown;
+        }
+    }
+}
+
+fn on_dialogue_queued(queued: On<DialogueQueued>, mut panel: ResMut<DialoguePanel>) {
+    if panel.requested && panel.npc == Some(queued.npc) {
+        panel.requested = false;
+        panel.pending = Some(queued.request);
+    }
+}
+
+/// Ends the pending reply with `text`
+fn finish_reply(
+    panel: &mut DialoguePanel,
+    npcs_q: &mut Query<&mut DialogueLog>,
+    request: RequestId,
+    text: String,
+) {
+    if panel.pending != Some(request) {
+        return;
+    }
+    panel.pending = None;
+
+    if let Some(npc) = panel.npc
+        && let Ok(mut log) = npcs_q.get_mut(npc)
+        && let Some(line) = log.0.last_mut()
+    {
+        line.text = text;
+    }
+}
+
+fn on_message_completed(
+    completed: On<MessageCompleted>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<&mut DialogueLog>,
+) {
+    finish_reply(
+        &mut panel,
+        &mut npcs_q,
+        completed.request,
+        completed.text.clone(),
+    );
+}
+
+fn on_dialogue_failed(
+    failed: On<DialogueFailed>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<&mut DialogueLog>,
+) {
+    finish_reply(
+        &mut panel,
+        &mut npcs_q,
+        failed.request,
+        "(says nothing)".to_string(),
+    );
+}
+
+fn on_dialogue_cancelled(
+    cancelled: On<DialogueCancelled>,
+    mut panel: ResMut<DialoguePanel>,
+    mut npcs_q: Query<&mut DialogueLog>,
+) {
+    finish_reply(
+        &mut panel,
+        &mut npcs_q,
+        cancelled.request,
+        "(trails off)".to_string(),
+    );
+}
+
+type PanelTexts = (
+    &'static mut Text,
+    Has<DialogueTitle>,
+    Has<DialogueHistoryText>,
+    Has<DialogueInput>,
+);
+
+fn update_dialogue_panel(
+    panel: Res<DialoguePanel>,
+    npcs_q: Query<(Option<&NpcPersona>, Option<&DialogueLog>)>,
+    mut root_q: Query<&mut Visibility, With<DialoguePanelRoot>>,
+    mut texts_q: Query<PanelTexts>,
+) {
+    let npc = panel.npc.and_then(|npc| npcs_q.get(npc).ok());
+
+    for mut visibility in &mut root_q {
+        visibility.set_if_neq(if npc.is_some() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        });
+    }
+
+    let Some((persona, log)) = npc else {
+        return;
+    };
+    let name = npc_name(persona);
+
+    let title = match persona {
+        Some(persona) => format!("{} the {} (Esc to leave)", persona.name, persona.role),
+        None => format!("{} (Esc to leave)", name),
+    };
+
+    let history = log
+        .iter()
+        .flat_map(|log| log.0.iter())
+        .map(|line| match line.speaker {
+            Speaker::Player => format!("You: {}", line.text),
+            Speaker::Npc => format!("{}: {}", name, line.text),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let input = if panel.is_answering() {
+        format!("{} is answering...", name)
+    } else {
+        format!("> {}_", panel.input)
+    };
+
+    for (mut text, is_title, is_history, is_input) in &mut texts_q {
+        let content = if is_title {
+            &title
+        } else if is_history {
+            &history
+        } else if is_input {
+            &input
+        } else {
+            continue;
+        };
+
+        if text.0 != *content {
+            text.0 = content.clone();
+        }
+    }
+}
+
+/// Mouse wheel scrolls the history, which sticks to the bottom unless the
+/// player scrolled up
+fn scroll_dialogue_history(
+    mut wheel: MessageReader<MouseWheel>,
+    mut panel: ResMut<DialoguePanel>,
+    mut history_q: Query<(&mut ScrollPosition, &ComputedNode), With<DialogueHistory>>,
+) {
+    let scrolled: f32 = wheel
+        .read()
+        .map(|wheel| match wheel.unit {
+            MouseScrollUnit::Line => wheel.y * SCROLL_LINE_HEIGHT,
+            MouseScrollUnit::Pixel => wheel.y,
+        })
+        .sum();
+
+    if panel.npc.is_none() {
+        return;
+    }
+
+    for (mut scroll, computed) in &mut history_q {
+        let max =
+            ((computed.content_size.y - computed.size.y) * computed.inverse_scale_factor).max(0.);
+
+        if scrolled != 0. {
+            scroll.y = (scroll.y - scrolled).clamp(0., max);
+            panel.follow_bottom = scroll.y >= max - 1.;
+        } else if panel.follow_bottom && scroll.y != max {
+            scroll.y = max;
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::VecDeque;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+    agent::Agent,
+    clock::GameClock,
+    decision::{TalkIntent, Threat},
+    persona::NpcPersona,
+    schedule::CurrentActivity,
+    visibility::VisibleTiles,
+    world::{
+        agent_idx::AgentIndex,
+        components::GridPosition,
+        rooms::{Room, RoomIndex},
+        spatial_idx::SpatialIndex,
+    },
+};
+
+/// Characters of live facts added to a prompt, about 4 characters a token
+const FACT_BUDGET_CHARS: usize = 480;
+
+/// Agents within this many tiles are mentioned
+const NEARBY_RADIUS: i32 = 6;
+
+/// Events an agent remembers, and for how many game minutes
+const MAX_RECENT_EVENTS: usize = 8;
+const EVENT_MEMORY_MINUTES: f64 = 180.;
+
+pub struct FactsPlugin;
+
+impl Plugin for FactsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_observer(on_add_agent)
+            .add_observer(on_world_event)
+            .add_observer(on_talk_intent)
+            .add_observer(on_add_threat);
+    }
+}
+
+/// Something happening at `at`, remembered by the agents that can see it
+#[derive(Event, Debug)]
+pub struct WorldEvent {
+    pub at: GridPosition,
+    pub description: String,
+    /// Agents taking part, they don't witness it
+    pub involved: Vec<Entity>,
+}
+
+/// What an agent saw or did lately, oldest first, with the game minute it
+/// happened
+#[derive(Component, Default, Debug)]
+pub struct RecentEvents(pub VecDeque<(f64, String)>);
+
+impl RecentEvents {
+    pub fn remember(&mut self, now: f64, text: String) {
+        self.0.push_back((now, text));
+        if self.0.len() > MAX_RECENT_EVENTS {
+            self.0.pop_front();
+        }
+    }
+}
+
+/// Line of the prompt, kept when it ranks high enough to fit the budget
+#[derive(Debug, Clone)]
+pub struct Fact {
+    pub text: String,
+    /// `0..=1`, higher first
+    pub relevance: f32,
+}
+
+fn on_add_agent(add: On<Add, Agent>, mut commands: Commands) {
+    commands.entity(add.entity).insert(RecentEvents::default());
+}
+
+fn on_world_event(
+    event: On<WorldEvent>,
+    mut witnesses_q: Query<(Entity, &VisibleTiles, &mut RecentEvents)>,
+    clock: Res<GameClock>,
+) {
+    let now = clock.total_minutes();
+
+    for (entity, visible, mut recent) in &mut witnesses_q {
+        if event.involved.contains(&entity) || !visible.tiles.contains(&(event.at.x, event.at.y)) {
+            continue;
+        }
+
+        recent.remember(now, format!("You saw {}", event.description));
+    }
+}
+
+/// Name an agent goes by in other agents' prompts
+pub fn display_name(persona: Option<&NpcPersona>) -> String {
+    persona.map_or("a stranger".to_string(), |persona| {
+        format!("{} the {}", persona.name, persona.role)
+    })
+}
+
+fn on_talk_intent(
+    talk: On<TalkIntent>,
+    agents_q: Query<(&GridPosition, Option<&NpcPersona>)>,
+    mut commands: Commands,
+) {
+    let Ok((at, speaker)) = agents_q.get(talk.speaker) else {
+        return;
+    };
+    let listener = agents_q
+        .get(talk.listener)
+        .ok()
+        .and_then(|(_, persona)| persona);
+
+    commands.trigger(WorldEvent {
+        at: at.clone(),
+        description: format!(
+            "{} stopped {} for a chat",
+            display_name(speaker),
+            display_name(listener)
+        ),
+        involved: vec![talk.speaker, talk.listener],
+    });
+}
+
+fn on_add_threat(
+    add: On<Add, Threat>,
+    agents_q: Query<(&GridPosition, Option<&NpcPersona>)>,
+    mut commands: Commands,
+) {
+    let Ok((at, persona)) = agents_q.get(add.entity) else {
+        return;
+    };
+
+    commands.trigger(WorldEvent {
+        at: at.clone(),
+        description: format!("{} turned menacing", display_name(persona)),
+        involved: vec![add.entity],
+    });
+}
+
+fn time_of_day(hour: u32) -> &'static str {
+    match hour {
+        5..=11 => "morning",
+        12..=16 => "afternoon",
+        17..=20 => "evening",
+        _ => "night",
+    }
+}
+
+type FactfulAgent = (
+    &'static GridPosition,
+    Option<&'static NpcPersona>,
+    Option<&'static CurrentActivity>,
+    Option<&'static RecentEvents>,
+);
+
+/// Renders the live simulation state around an NPC into prompt facts
+#[derive(SystemParam)]
+pub struct FactGatherer<'w, 's> {
+    clock: Res<'w, GameClock>,
+    room_idx: Res<'w, RoomIndex>,
+    spatial_idx: Res<'w, SpatialIndex>,
+    agent_idx: Res<'w, AgentIndex>,
+    rooms_q: Query<'w, 's, &'static Room>,
+    agents_q: Query<'w, 's, FactfulAgent, With<Agent>>,
+}
+
+impl FactGatherer<'_, '_> {
+    /// Every fact about the NPC's situation, unranked
+    pub fn gather(&self, npc: Entity) -> Vec<Fact> {
+        let mut facts = vec![Fact {
+            text: format!(
+                "It is {}, {:02}:{:02} on day {}",
+                time_of_day(self.clock.hour()),
+                self.clock.hour(),
+                self.clock.minute(),
+                self.clock.day() + 1
+            ),
+            relevance: 0.6,
+        }];
+
+        let Ok((position, _, activity, recent)) = self.agents_q.get(npc) else {
+            return facts;
+        };
+
+        if let Some(place) = self.place_of(position) {
+            facts.push(Fact {
+                text: place,
+                relevance: 0.8,
+            });
+        }
+
+        if let Some(name) = activity.and_then(|activity| activity.name.as_ref()) {
+            facts.push(Fact {
+                text: format!("You are busy {}", name),
+                relevance: 0.9,
+            });
+        }
+
+        for (other, other_position) in self.agent_idx.agents_in_radius(position, NEARBY_RADIUS) {
+            if other == npc {
+                continue;
+            }
+
+            let persona = self
+                .agents_q
+                .get(other)
+                .ok()
+                .and_then(|(_, persona, ..)| persona);
+            facts.push(Fact {
+                text: format!("{} is nearby", display_name(persona)),
+                relevance: 0.7 - 0.05 * position.distance(other_position),
+            });
+        }
+
+        let now = self.clock.total_minutes();
+        for (at, memory) in recent.iter().flat_map(|recent| recent.0.iter()) {
+            let age = now - at;
+            if age > EVENT_MEMORY_MINUTES {
+                continue;
+            }
+
+            facts.push(Fact {
+                text: format!("{} ({} minutes ago)", memory, age.round() as u32),
+                relevance: 1. - 0.5 * (age / EVENT_MEMORY_MINUTES) as f32,
+            });
+        }
+
+        facts
+    }
+
+    /// Room, building or outdoors, `None` off the map
+    fn place_of(&self, position: &GridPosition) -> Option<String> {
+        let room_name = self
+            .room_idx
+            .room_at(position.x, position.y)
+            .and_then(|room| self.rooms_q.get(room).ok())
+            .and_then(|room| room.name.as_ref());
+
+        if let Some(name) = room_name {
+            return Some(format!("You are in the {}", name));
+        }
+
+        if self.room_idx.building_at(position.x, position.y).is_some() {
+            return Some("You are inside a building".to_string());
+        }
+
+        self.spatial_idx
+            .get(position.x, position.y)
+            .filter(|tile_data| tile_data.is_outside())
+            .map(|_| "You are outdoors, in the streets of the town".to_string())
+    }
+
+    /// Most relevant facts fitting `FACT_BUDGET_CHARS`
+    pub fn ranked(&self, npc: Entity) -> Vec<String> {
+        let mut facts = self.gather(npc);
+        facts.sort_by(|a, b| b.relevance.total_cmp(&a.relevance));
+
+        let mut budget = FACT_BUDGET_CHARS;
+        facts
+            .into_iter()
+            .filter(|fact| {
+                let fits = fact.text.len() <= budget;
+                if fits {
+                    budget -= fact.text.len();
+                }
+                fits
+            })
+            .map(|fact| fact.text)
+            .collect()
+    }
+
+    /// System prompt of the NPC: its persona, strangers for agents without
+    /// one yet, and its `situation` from `ranked`
+    pub fn system_prompt(&self, npc: Entity, situation: &[String]) -> String {
+        match self
+            .agents_q
+            .get(npc)
+            .ok()
+            .and_then(|(_, persona, ..)| persona)
+        {
+            Some(persona) => persona.system_prompt(situation),
+            None => NpcPersona::default().system_prompt(situation),
+        }
+    }
+}
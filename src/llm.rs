@@ -0,0 +1,779 @@
+use std::{ops::ControlFlow, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail};
+use bevy::log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Backend settings, an `LlmConfig` in JSON. Falls back to Ollama when missing.
+const LLM_CONFIG_PATH: &str = "assets/llm.json";
+
+/// Overrides `LLM_CONFIG_PATH`, e.g. to run the scripted backend in CI
+const LLM_CONFIG_ENV: &str = "LLM_CONFIG";
+
+const OLLAMA_URL: &str = "http://localhost:11434";
+const OPENAI_URL: &str = "http://localhost:8080";
+const DEFAULT_MODEL: &str = "phi3:3.8b";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// A stream sending nothing for this long is dropped
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Prefix of the lines of an OpenAI event stream
+const SSE_DATA: &str = "data:";
+const SSE_DONE: &str = "[DONE]";
+
+/// Earlier exchange of a conversation
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub prompt: String,
+    pub response: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sampling {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub max_tokens: usize,
+    pub repeat_penalty: f32,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            temperature: 0.6,
+            top_p: 0.9,
+            max_tokens: 80,
+            repeat_penalty: 1.1,
+        }
+    }
+}
+
+/// One turn to generate. Backends keep the conversation either through the
+/// latest turns in `history` or through the opaque `context` they returned
+/// last time. Older turns are remembered through the system prompt.
+#[derive(Debug, Clone, Default)]
+pub struct LlmRequest {
+    pub system: String,
+    /// What the system prompt says about now: the situation and recalled
+    /// memories, sent instead of it by turns continued from a `context`
+    pub recap: String,
+    pub history: Vec<Turn>,
+    pub prompt: String,
+    pub context: Option<Vec<u32>>,
+    pub sampling: Sampling,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmResponse {
+    pub text: String,
+    /// State to send back with the next turn, for backends that have one
+    pub context: Option<Vec<u32>>,
+}
+
+/// Something that turns prompts into NPC lines. Called from worker threads,
+/// so implementations may block.
+pub trait LlmBackend: Send + Sync {
+    /// Generates the reply to `request`, handing each piece to `on_token` as
+    /// it is read. An error from `on_token` stops the generation.
+    fn generate(
+        &self,
+        request: &LlmRequest,
+        on_token: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<LlmResponse>;
+
+    /// Embedding of `text`, `None` when the backend has no embedding model
+    fn embed(&self, _text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+        Ok(None)
+    }
+}
+
+/// Runs `f` on a thread of the blocking pool, so a backend waiting on HTTP
+/// does not hold up the IO task pool, which also loads the assets
+pub async fn unblock<T: Send + 'static>(
+    backend: &Arc<dyn LlmBackend>,
+    f: impl FnOnce(&dyn LlmBackend) -> T + Send + 'static,
+) -> T {
+    let backend = backend.clone();
+    blocking::unblock(move || f(&*backend)).await
+}
+
+/// Which backend to use, read from `LLM_CONFIG_PATH`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum LlmConfig {
+    OllamaGenerate {
+        #[serde(default = "ollama_url")]
+        url: String,
+        #[serde(default = "default_model")]
+        model: String,
+        /// Guessed from the model name when missing
+        #[serde(default)]
+        template: Option<ChatTemplate>,
+        /// Model of `/api/embed`, memories are recalled by keyword without
+        #[serde(default)]
+        embedding_model: Option<String>,
+    },
+    OllamaChat {
+        #[serde(default = "ollama_url")]
+        url: String,
+        #[serde(default = "default_model")]
+        model: String,
+        #[serde(default)]
+        embedding_model: Option<String>,
+    },
+    /// llama.cpp server, vLLM and other `/v1/chat/completions` servers
+    OpenAi {
+        #[serde(default = "openai_url")]
+        url: String,
+        #[serde(default = "default_model")]
+        model: String,
+        #[serde(default)]
+        api_key: Option<String>,
+        /// Model of `/v1/embeddings`
+        #[serde(default)]
+        embedding_model: Option<String>,
+    },
+    Scripted {
+        replies: Vec<String>,
+    },
+}
+
+fn ollama_url() -> String {
+    OLLAMA_URL.to_string()
+}
+
+fn openai_url() -> String {
+    OPENAI_URL.to_string()
+}
+
+fn default_model() -> String {
+    DEFAULT_MODEL.to_string()
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self::OllamaGenerate {
+            url: ollama_url(),
+            model: default_model(),
+            template: None,
+            embedding_model: None,
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn load() -> Self {
+        let path = std::env::var(LLM_CONFIG_ENV).unwrap_or_else(|_| LLM_CONFIG_PATH.to_string());
+
+        let parsed = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str::<LlmConfig>(&json)?));
+
+        match parsed {
+            Ok(config) => {
+                info!("LLM backend from {}: {:?}", path, config);
+                config
+            }
+            Err(err) => {
+                warn!(
+                    "using the default LLM backend, cannot read {}: {:?}",
+                    path, err
+                );
+                Self::default()
+            }
+        }
+    }
+
+    pub fn backend(self) -> Box<dyn LlmBackend> {
+        match self {
+            Self::OllamaGenerate {
+                url,
+                model,
+                template,
+                embedding_model,
+            } => Box::new(OllamaGenerate {
+                template: template.unwrap_or_else(|| ChatTemplate::for_model(&model)),
+                url,
+                model,
+                embedding_model,
+            }),
+            Self::OllamaChat {
+                url,
+                model,
+                embedding_model,
+            } => Box::new(OllamaChat {
+                url,
+                model,
+                embedding_model,
+            }),
+            Self::OpenAi {
+                url,
+                model,
+                api_key,
+                embedding_model,
+            } => Box::new(OpenAiCompatible {
+                url,
+                model,
+                api_key,
+                embedding_model,
+            }),
+            Self::Scripted { replies } => Box::new(Scripted { replies }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+}
+
+/// System prompt, earlier turns and the new prompt as chat messages
+fn chat_messages(request: &LlmRequest) -> Vec<ChatMessage> {
+    let mut messages = vec![ChatMessage {
+        role: "system",
+        content: request.system.clone(),
+    }];
+
+    for turn in &request.history {
+        messages.push(ChatMessage {
+            role: "user",
+            content: turn.prompt.clone(),
+        });
+        messages.push(ChatMessage {
+            role: "assistant",
+            content: turn.response.clone(),
+        });
+    }
+
+    messages.push(ChatMessage {
+        role: "user",
+        content: request.prompt.clone(),
+    });
+    messages
+}
+
+fn json_request(
+    url: &str,
+    body: &impl Serialize,
+    api_key: Option<&str>,
+) -> anyhow::Result<ehttp::Request> {
+    let mut http_request =
+        ehttp::Request::post(url, serde_json::to_vec(body)?).with_timeout(Some(REQUEST_TIMEOUT));
+
+    http_request.headers =
+        ehttp::Headers::new(&[("Accept", "*/*"), ("Content-Type", "application/json")]);
+    if let Some(api_key) = api_key {
+        http_request
+            .headers
+            .insert("Authorization", format!("Bearer {}", api_key));
+    }
+
+    Ok(http_request)
+}
+
+/// Posts `body` as JSON and returns the response text
+fn post_json(url: &str, body: &impl Serialize, api_key: Option<&str>) -> anyhow::Result<String> {
+    let res =
+        ehttp::fetch_blocking(&json_request(url, body, api_key)?).map_err(|err| anyhow!(err))?;
+    if !res.ok {
+        bail!("{} answered {} {}", url, res.status, res.status_text);
+    }
+
+    res.text()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("response is not text"))
+}
+
+/// Posts `body` as JSON and hands each line of the response to `on_line` as
+/// soon as it arrives. Returning `Break` or an error from `on_line` stops
+/// reading and drops the connection.
+fn post_json_streaming(
+    url: &str,
+    body: &impl Serialize,
+    api_key: Option<&str>,
+    on_line: &mut dyn FnMut(&str) -> anyhow::Result<ControlFlow<()>>,
+) -> anyhow::Result<()> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+
+    ehttp::streaming::fetch(json_request(url, body, api_key)?, move |part| {
+        // Nobody reads the parts once the receiver is dropped
+        match sender.send(part) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    });
+
+    let mut buffer = Vec::new();
+
+    loop {
+        let part = receiver
+            .recv_timeout(STREAM_IDLE_TIMEOUT)
+            .map_err(|err| anyhow!("{} stopped streaming: {}", url, err))?
+            .map_err(|err| anyhow!(err))?;
+
+        let chunk = match part {
+            ehttp::streaming::Part::Response(res) if !res.ok => {
+                bail!("{} answered {} {}", url, res.status, res.status_text);
+            }
+            ehttp::streaming::Part::Response(_) => continue,
+            ehttp::streaming::Part::Chunk(chunk) => chunk,
+        };
+
+        // An empty chunk ends the body, which may not end with a newline
+        let done = chunk.is_empty();
+        buffer.extend_from_slice(&chunk);
+        if done && !buffer.ends_with(b"\n") {
+            buffer.push(b'\n');
+        }
+
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = std::str::from_utf8(&line)?.trim();
+
+            if !line.is_empty() && on_line(line)?.is_break() {
+                return Ok(());
+            }
+        }
+
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// Embedding from Ollama's `/api/embed`
+fn ollama_embed(url: &str, model: Option<&str>, text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+    let Some(model) = model else {
+        return Ok(None);
+    };
+
+    let body = EmbedRequest { model, input: text };
+    let res: OllamaEmbedResponse =
+        serde_json::from_str(&post_json(&format!("{}/api/embed", url), &body, None)?)?;
+
+    Ok(res.embeddings.into_iter().next())
+}
+
+// #### Chat templates
+
+/// How a model expects the turns of a conversation to be laid out in a raw
+/// prompt
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatTemplate {
+    Phi3,
+    Llama3,
+    ChatMl,
+}
+
+impl ChatTemplate {
+    /// Template of a model from its name, ChatML for unknown families
+    pub fn for_model(model: &str) -> Self {
+        let model = model.to_lowercase().replace(['-', '_', '.'], "");
+
+        if model.contains("phi3") {
+            Self::Phi3
+        } else if model.contains("llama3") {
+            Self::Llama3
+        } else {
+            Self::ChatMl
+        }
+    }
+
+    fn message(self, role: &str, content: &str) -> String {
+        match self {
+            Self::Phi3 => format!("<|{}|>\n{}<|end|>\n", role, content),
+            Self::Llama3 => format!(
+                "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                role, content
+            ),
+            Self::ChatMl => format!("<|im_start|>{}\n{}<|im_end|>\n", role, content),
+        }
+    }
+
+    /// Opens the reply the model completes
+    fn assistant(self) -> &'static str {
+        match self {
+            Self::Phi3 => "<|assistant|>\n",
+            Self::Llama3 => "<|start_header_id|>assistant<|end_header_id|>\n\n",
+            Self::ChatMl => "<|im_start|>assistant\n",
+        }
+    }
+
+    /// Token ending the reply
+    pub fn stop(self) -> &'static str {
+        match self {
+            Self::Phi3 => "<|end|>",
+            Self::Llama3 => "<|eot_id|>",
+            Self::ChatMl => "<|im_end|>",
+        }
+    }
+
+    /// Raw prompt of the turn. When the conversation goes on from a backend
+    /// context, only the recap and the new prompt are rendered.
+    pub fn render(self, request: &LlmRequest, continued: bool) -> String {
+        let mut prompt = String::new();
+
+        if continued {
+            let recap = request.recap.trim();
+            if !recap.is_empty() {
+                prompt.push_str(&self.message("system", recap));
+            }
+        } else {
+            if self == Self::Llama3 {
+                prompt.push_str("<|begin_of_text|>");
+            }
+            prompt.push_str(&self.message("system", request.system.trim()));
+
+            for turn in &request.history {
+                prompt.push_str(&self.message("user", &turn.prompt));
+                prompt.push_str(&self.message("assistant", &turn.response));
+            }
+        }
+
+        prompt.push_str(&self.message("user", &request.prompt));
+        prompt.push_str(self.assistant());
+        prompt
+    }
+}
+
+// #### Ollama /api/generate
+
+/// Ollama raw completion, the conversation is carried by Ollama's context
+pub struct OllamaGenerate {
+    url: String,
+    model: String,
+    template: ChatTemplate,
+    embedding_model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GenerateRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    /// The prompt is already laid out in the model's template
+    raw: bool,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<&'a [u32]>,
+}
+
+/// Sampling settings, which Ollama only reads from `options`
+#[derive(Serialize)]
+struct OllamaOptions {
+    temperature: f32,
+    top_p: f32,
+    num_predict: usize,
+    repeat_penalty: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GenerateResponse {
+    response: String,
+    context: Option<Vec<u32>>,
+    done: bool,
+}
+
+impl LlmBackend for OllamaGenerate {
+    fn generate(
+        &self,
+        request: &LlmRequest,
+        on_token: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<LlmResponse> {
+        let context = request
+            .context
+            .as_deref()
+            .filter(|context| !context.is_empty());
+
+        let body = GenerateRequest {
+            model: &self.model,
+            prompt: self.template.render(request, context.is_some()),
+            raw: true,
+            stream: true,
+            options: OllamaOptions {
+                temperature: request.sampling.temperature,
+                top_p: request.sampling.top_p,
+                num_predict: request.sampling.max_tokens,
+                repeat_penalty: request.sampling.repeat_penalty,
+                stop: vec![self.template.stop().to_string()],
+            },
+            context,
+        };
+
+        let mut response = LlmResponse {
+            text: String::new(),
+            context: None,
+        };
+
+        // One JSON object per line
+        post_json_streaming(
+            &format!("{}/api/generate", self.url),
+            &body,
+            None,
+            &mut |line| {
+                let chunk: GenerateResponse = serde_json::from_str(line)?;
+
+                if !chunk.response.is_empty() {
+                    on_token(&chunk.response)?;
+                    response.text.push_str(&chunk.response);
+                }
+
+                if chunk.done {
+                    response.context = chunk.context;
+                    return Ok(ControlFlow::Break(()));
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+
+        Ok(response)
+    }
+
+    fn embed(&self, text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+        ollama_embed(&self.url, self.embedding_model.as_deref(), text)
+    }
+}
+
+// #### Ollama /api/chat
+
+/// Ollama chat, the history is sent with every turn
+pub struct OllamaChat {
+    url: String,
+    model: String,
+    embedding_model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OllamaChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: OllamaOptions,
+}
+
+#[derive(Deserialize)]
+struct OllamaChatResponse {
+    message: Option<OllamaChatMessage>,
+    done: bool,
+}
+
+#[derive(Deserialize)]
+struct OllamaChatMessage {
+    content: String,
+}
+
+impl LlmBackend for OllamaChat {
+    fn generate(
+        &self,
+        request: &LlmRequest,
+        on_token: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<LlmResponse> {
+        let body = OllamaChatRequest {
+            model: &self.model,
+            messages: chat_messages(request),
+            stream: true,
+            options: OllamaOptions {
+                temperature: request.sampling.temperature,
+                top_p: request.sampling.top_p,
+                num_predict: request.sampling.max_tokens,
+                repeat_penalty: request.sampling.repeat_penalty,
+                stop: vec![],
+            },
+        };
+
+        let mut reply = String::new();
+
+        post_json_streaming(
+            &format!("{}/api/chat", self.url),
+            &body,
+            None,
+            &mut |line| {
+                let chunk: OllamaChatResponse = serde_json::from_str(line)?;
+
+                if let Some(message) = chunk.message
+                    && !message.content.is_empty()
+                {
+                    on_token(&message.content)?;
+                    reply.push_str(&message.content);
+                }
+
+                Ok(if chunk.done {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            },
+        )?;
+
+        Ok(LlmResponse {
+            text: reply,
+            context: None,
+        })
+    }
+
+    fn embed(&self, text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+        ollama_embed(&self.url, self.embedding_model.as_deref(), text)
+    }
+}
+
+// #### OpenAI-compatible /v1/chat/completions
+
+pub struct OpenAiCompatible {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    embedding_model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    temperature: f32,
+    top_p: f32,
+    max_tokens: usize,
+    /// Not part of the OpenAI API, understood by llama.cpp and vLLM
+    repeat_penalty: f32,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    delta: CompletionDelta,
+}
+
+#[derive(Deserialize)]
+struct CompletionDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+}
+
+impl LlmBackend for OpenAiCompatible {
+    fn generate(
+        &self,
+        request: &LlmRequest,
+        on_token: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<LlmResponse> {
+        let body = CompletionRequest {
+            model: &self.model,
+            messages: chat_messages(request),
+            stream: true,
+            temperature: request.sampling.temperature,
+            top_p: request.sampling.top_p,
+            max_tokens: request.sampling.max_tokens,
+            repeat_penalty: request.sampling.repeat_penalty,
+        };
+
+        let mut reply = String::new();
+
+        // Server-sent events, one JSON chunk per `data:` line
+        post_json_streaming(
+            &format!("{}/v1/chat/completions", self.url),
+            &body,
+            self.api_key.as_deref(),
+            &mut |line| {
+                let Some(data) = line.strip_prefix(SSE_DATA).map(str::trim) else {
+                    return Ok(ControlFlow::Continue(()));
+                };
+
+                if data == SSE_DONE {
+                    return Ok(ControlFlow::Break(()));
+                }
+
+                let chunk: CompletionChunk = serde_json::from_str(data)?;
+                for content in chunk
+                    .choices
+                    .into_iter()
+                    .filter_map(|choice| choice.delta.content)
+                    .filter(|content| !content.is_empty())
+                {
+                    on_token(&content)?;
+                    reply.push_str(&content);
+                }
+
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+
+        Ok(LlmResponse {
+            text: reply,
+            context: None,
+        })
+    }
+
+    fn embed(&self, text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+        let Some(model) = self.embedding_model.as_deref() else {
+            return Ok(None);
+        };
+
+        let body = EmbedRequest { model, input: text };
+        let res: EmbeddingsResponse = serde_json::from_str(&post_json(
+            &format!("{}/v1/embeddings", self.url),
+            &body,
+            self.api_key.as_deref(),
+        )?)?;
+
+        Ok(res.data.into_iter().next().map(|data| data.embedding))
+    }
+}
+
+// #### Scripted
+
+/// Offline stand-in. Answers the n-th turn of a conversation with the n-th
+/// reply, wrapping around, streamed word by word.
+pub struct Scripted {
+    replies: Vec<String>,
+}
+
+impl LlmBackend for Scripted {
+    fn generate(
+        &self,
+        request: &LlmRequest,
+        on_token: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<LlmResponse> {
+        if self.replies.is_empty() {
+            bail!("scripted backend has no replies");
+        }
+
+        let reply = &self.replies[request.history.len() % self.replies.len()];
+
+        for token in reply.split_inclusive(' ') {
+            on_token(token)?;
+        }
+
+        Ok(LlmResponse {
+            text: reply.clone(),
+            context: None,
+        })
+    }
+}
@@ -1,23 +1,48 @@
 mod agent;
+mod ambient;
 mod animation;
+mod appearance;
 mod background;
+mod camera;
+mod clock;
 mod constants;
+mod decision;
+mod depth;
+mod dialogue;
 mod events;
+mod facts;
+mod llm;
+mod memory;
 mod message_animation;
+mod named_folder;
 mod pathfinder;
+mod persona;
 mod roof;
+mod schedule;
+mod visibility;
 mod world;
 
 use agent::{Agent, AgentPlugin, Walking};
+use ambient::AmbientChatPlugin;
 use animation::AnimationPlugin;
+use appearance::AppearancePlugin;
 use background::BackgroundPlugin;
 use bevy::{color::palettes::css::*, prelude::*};
 use bevy_ecs_ldtk::prelude::*;
 use bevy_ecs_tilemap::tiles::{TileColor, TilePos};
+use camera::CameraFollowPlugin;
+use clock::ClockPlugin;
 use constants::*;
+use decision::DecisionPlugin;
+use depth::DepthPlugin;
+use dialogue::DialoguePlugin;
+use facts::FactsPlugin;
 use message_animation::MessageAnimationPlugin;
+use persona::PersonaPlugin;
 use roof::RoofPlugin;
-use world::{components::*, grid::*, plugin::*, spatial_idx::*};
+use schedule::SchedulePlugin;
+use visibility::{FieldOfViewPlugin, line_of_sight};
+use world::{agent_idx::*, components::*, grid::*, plugin::*, rooms::Room, spatial_idx::*};
 
 fn main() {
     App::new()
@@ -28,15 +53,34 @@ fn main() {
         .add_plugins(WorldPlugin)
         .add_plugins(BackgroundPlugin)
         .add_plugins(AnimationPlugin)
+        .add_plugins(AppearancePlugin)
         .add_plugins(MessageAnimationPlugin)
+        .add_plugins(DialoguePlugin)
+        .add_plugins(FieldOfViewPlugin)
+        .add_plugins(CameraFollowPlugin)
+        .add_plugins(DepthPlugin)
+        .add_plugins(ClockPlugin)
+        .add_plugins(SchedulePlugin)
+        .add_plugins(PersonaPlugin)
+        .add_plugins(FactsPlugin)
+        .add_plugins(DecisionPlugin)
+        .add_plugins(AmbientChatPlugin)
         .init_resource::<GizmoConfigStore>()
         .insert_resource(LevelSelection::index(0))
+        .insert_resource(LdtkSettings {
+            level_spawn_behavior: LevelSpawnBehavior::UseWorldTranslation {
+                load_level_neighbors: true,
+            },
+            ..default()
+        })
         .add_systems(PreStartup, setup_camera)
         .add_systems(
             Update,
             (
                 mark_destination_on_map,
                 draw_tile_gizmos,
+                draw_agent_neighbourhood_gizmos,
+                draw_room_gizmos,
                 toggle_gizmos,
                 // debug
             ),
@@ -44,7 +88,6 @@ fn main() {
         .run();
 }
 
-
 // TilemapBundle
 fn debug(query: Query<(Entity, &TilePos, &Transform, &TileColor)>) {
     for (entity, tile_pos, transform, tile_color) in query {
@@ -84,8 +127,11 @@ fn setup_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
         Transform::from_xyz(512.0, 512.0, 0.0),
     ));
 
+    // Levels hang below the world origin, the first one is lifted onto the
+    // grid rows `0..GRID_HEIGHT`
     commands.spawn(LdtkWorldBundle {
         ldtk_handle: asset_server.load("proj.ldtk").into(),
+        transform: Transform::from_xyz(0., GRID_HEIGHT as f32 * TILE_SIZE, 0.),
 
         ..Default::default()
     });
@@ -130,12 +176,23 @@ fn mark_destination_on_map(query: Query<&Walking, With<Agent>>, mut gizmos: Gizm
 fn draw_tile_gizmos(
     spatial_idx: Res<SpatialIndex>,
 
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+
     occupied_query: Query<&GridPosition, With<Occupied>>,
 
     mut gizmos: Gizmos,
 ) {
+    let Ok((camera, camera_transform)) = camera_query.single() else {
+        return;
+    };
+
+    // Only visit the chunks under the camera
+    let Some((min, max)) = Grid::camera_view_rect(camera, camera_transform) else {
+        return;
+    };
+
     // First, draw gizmos for TileTypes
-    for (coords_tuple, tile_data) in &spatial_idx.map {
+    for (coords_tuple, tile_data) in spatial_idx.iter_rect(min, max) {
         let pos = Grid::grid_to_world(coords_tuple.0, coords_tuple.1);
 
         let color = if tile_data.flags.contains(TileFlags::INSIDE) {
@@ -171,6 +228,62 @@ fn draw_tile_gizmos(
     }
 }
 
+fn draw_room_gizmos(rooms_q: Query<&Room>, mut gizmos: Gizmos) {
+    for room in &rooms_q {
+        let min = Grid::grid_to_world(room.min.x, room.min.y).truncate();
+        let max = Grid::grid_to_world(room.max.x, room.max.y).truncate();
+
+        gizmos.rect_2d((min + max) / 2., max - min + Vec2::splat(TILE_SIZE), ORANGE);
+
+        for door in &room.doors {
+            let pos = Grid::grid_to_world(door.x, door.y);
+
+            gizmos.circle_2d(pos.truncate(), TILE_SIZE / 3.0, ORANGE);
+        }
+    }
+}
+
+/// Links the agent closest to the cursor with its neighbours, green when they
+/// can see each other and red when a wall is in the way
+fn draw_agent_neighbourhood_gizmos(
+    cursor_tile: Res<CursorTile>,
+    spatial_idx: Res<SpatialIndex>,
+    agent_idx: Res<AgentIndex>,
+    mut gizmos: Gizmos,
+) {
+    const NEIGHBOURHOOD_RADIUS: i32 = 5;
+
+    let Some(cursor_tile) = &cursor_tile.0 else {
+        return;
+    };
+
+    let Some((agent_entity, agent_pos)) = agent_idx.nearest_agent(cursor_tile, 2, None) else {
+        return;
+    };
+
+    let origin = Grid::grid_to_world(agent_pos.x, agent_pos.y).truncate();
+
+    gizmos.circle_2d(origin, TILE_SIZE * NEIGHBOURHOOD_RADIUS as f32, WHITE);
+
+    for (neighbour, neighbour_pos) in agent_idx.agents_in_radius(agent_pos, NEIGHBOURHOOD_RADIUS) {
+        if neighbour == agent_entity {
+            continue;
+        }
+
+        let color = if line_of_sight(&spatial_idx, agent_pos, neighbour_pos) {
+            GREEN
+        } else {
+            RED
+        };
+
+        gizmos.line_2d(
+            origin,
+            Grid::grid_to_world(neighbour_pos.x, neighbour_pos.y).truncate(),
+            color,
+        );
+    }
+}
+
 fn on_disocuppied(mut removed: RemovedComponents<Occupied>, query: Query<&Tile>) {
     for entity in removed.read() {
         if let Ok(tile) = query.get(entity) {
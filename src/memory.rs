@@ -0,0 +1,323 @@
+use std::{collections::HashSet, sync::Arc};
+
+use bevy::log::warn;
+
+use crate::llm::{LlmBackend, LlmRequest, Sampling, unblock};
+
+/// Old turns are summarised by batches of this many
+const SUMMARY_TURNS: usize = 6;
+
+/// Memories weighed for a prompt, the most recent ones
+const MAX_CANDIDATES: i64 = 200;
+/// Memories added to a prompt on top of the last summary of the conversation
+const MAX_RECALLED: usize = 3;
+/// Cosine similarity below which an embedded memory is not recalled
+const MIN_SIMILARITY: f32 = 0.35;
+
+/// Shorter words are too common to tell memories apart
+const MIN_KEYWORD_LEN: usize = 4;
+const STOPWORDS: [&str; 16] = [
+    "that", "this", "with", "have", "your", "what", "they", "them", "there", "their", "about",
+    "from", "were", "will", "would", "been",
+];
+
+const SUMMARY_SYSTEM: &str = "You keep the memories of an NPC in a medieval fantasy RPG. \
+Summarise the conversation below in at most two sentences, speaking to the NPC as \"you\". \
+Keep names, promises and facts learned. Answer with the summary only.";
+
+/// The NPC owning the memories of a conversation: its `ConversationId`, which
+/// the conversation ID starts with
+pub fn owner(conversation_id: &str) -> &str {
+    conversation_id
+        .split_once(':')
+        .map_or(conversation_id, |(npc, _)| npc)
+}
+
+pub async fn create_tables(conn: &libsql::Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS npc_memories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            npc TEXT NOT NULL,
+            conversation_id TEXT NOT NULL,
+            text TEXT NOT NULL,
+            embedding TEXT,
+            through_turn INTEGER NOT NULL,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        ",
+        (),
+    )
+    .await?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS npc_memories_npc ON npc_memories (npc)",
+        (),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Adds the memories to the system prompt of the NPC
+pub fn with_memories(system: String, memories: &[String]) -> String {
+    if memories.is_empty() {
+        return system;
+    }
+
+    let mut prompt = system;
+    prompt.push_str("\n\nYOU REMEMBER:\n");
+    for memory in memories {
+        prompt.push_str(&format!("- {}\n", memory));
+    }
+    prompt
+}
+
+/// Memories of the NPC worth bringing up for `prompt`: the most similar ones,
+/// by embedding when the backend has an embedding model and by keyword
+/// otherwise, then the last summary of the conversation
+pub async fn recall(
+    conn: &libsql::Connection,
+    backend: &Arc<dyn LlmBackend>,
+    conversation_id: &str,
+    prompt: &str,
+) -> anyhow::Result<Vec<String>> {
+    let mut rows = conn
+        .query(
+            "SELECT text FROM npc_memories WHERE conversation_id = ?1 ORDER BY id DESC LIMIT 1",
+            [conversation_id],
+        )
+        .await?;
+    let last_summary = match rows.next().await? {
+        Some(row) => Some(row.get_str(0)?.to_string()),
+        None => None,
+    };
+
+    let mut rows = conn
+        .query(
+            "SELECT text, embedding FROM npc_memories WHERE npc = ?1 ORDER BY id DESC LIMIT ?2",
+            (owner(conversation_id), MAX_CANDIDATES),
+        )
+        .await?;
+
+    let mut candidates = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let text = row.get_str(0)?.to_string();
+        if last_summary.as_ref() == Some(&text) {
+            continue;
+        }
+
+        let embedding = row
+            .get::<Option<String>>(1)?
+            .map(|json| serde_json::from_str::<Vec<f32>>(&json))
+            .transpose()?;
+        candidates.push((text, embedding));
+    }
+
+    let query_embedding = if candidates.iter().any(|(_, embedding)| embedding.is_some()) {
+        let prompt = prompt.to_string();
+        unblock(backend, move |backend| backend.embed(&prompt))
+            .await
+            .unwrap_or_else(|err| {
+                warn!("recalling by keyword, cannot embed the prompt: {:?}", err);
+                None
+            })
+    } else {
+        None
+    };
+    let query_keywords = keywords(prompt);
+
+    let mut scored: Vec<(f32, String)> = candidates
+        .into_iter()
+        .filter_map(|(text, embedding)| {
+            let score = match (&query_embedding, embedding) {
+                (Some(query), Some(embedding)) => {
+                    Some(cosine_similarity(query, &embedding)).filter(|s| *s >= MIN_SIMILARITY)
+                }
+                _ => Some(keyword_overlap(&query_keywords, &text)).filter(|s| *s > 0.),
+            }?;
+            Some((score, text))
+        })
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    let mut memories: Vec<String> = scored
+        .into_iter()
+        .take(MAX_RECALLED)
+        .map(|(_, text)| text)
+        .collect();
+    memories.extend(last_summary);
+    Ok(memories)
+}
+
+/// Summarises the oldest turns of the conversation the history no longer
+/// carries, keeping the last `kept_turns` as they are
+pub async fn consolidate(
+    conn: &libsql::Connection,
+    backend: &Arc<dyn LlmBackend>,
+    conversation_id: &str,
+    kept_turns: usize,
+) -> anyhow::Result<()> {
+    let mut rows = conn
+        .query(
+            "SELECT COALESCE(MAX(through_turn), 0) FROM npc_memories WHERE conversation_id = ?1",
+            [conversation_id],
+        )
+        .await?;
+    let summarised = match rows.next().await? {
+        Some(row) => row.get::<i64>(0)?,
+        None => 0,
+    };
+
+    let mut rows = conn
+        .query(
+            "SELECT id, prompt, response FROM conversation_turns WHERE conversation_id = ?1 AND id > ?2 ORDER BY id LIMIT ?3",
+            (
+                conversation_id,
+                summarised,
+                (kept_turns + SUMMARY_TURNS) as i64,
+            ),
+        )
+        .await?;
+
+    let mut turns = Vec::new();
+    while let Some(row) = rows.next().await? {
+        turns.push((
+            row.get::<i64>(0)?,
+            row.get_str(1)?.to_string(),
+            row.get_str(2)?.to_string(),
+        ));
+    }
+
+    if turns.len() < kept_turns + SUMMARY_TURNS {
+        return Ok(());
+    }
+
+    let mut transcript = String::new();
+    for (_, prompt, response) in &turns[..SUMMARY_TURNS] {
+        transcript.push_str(&format!(
+            "They said: {}\nYou answered: {}\n",
+            prompt, response
+        ));
+    }
+    let through_turn = turns[SUMMARY_TURNS - 1].0;
+
+    let summary = summarise(backend, conversation_id, transcript).await?;
+    insert(conn, backend, conversation_id, &summary, through_turn).await
+}
+
+/// Keeps a summary of a chat the NPC had with another NPC, `transcript` being
+/// told from its point of view. The turns of the chat count as summarised.
+pub async fn remember_chat(
+    conn: &libsql::Connection,
+    backend: &Arc<dyn LlmBackend>,
+    conversation_id: &str,
+    transcript: String,
+) -> anyhow::Result<()> {
+    let mut rows = conn
+        .query(
+            "SELECT COALESCE(MAX(id), 0) FROM conversation_turns WHERE conversation_id = ?1",
+            [conversation_id],
+        )
+        .await?;
+    let through_turn = match rows.next().await? {
+        Some(row) => row.get::<i64>(0)?,
+        None => 0,
+    };
+
+    let summary = summarise(backend, conversation_id, transcript).await?;
+    insert(conn, backend, conversation_id, &summary, through_turn).await
+}
+
+async fn summarise(
+    backend: &Arc<dyn LlmBackend>,
+    conversation_id: &str,
+    transcript: String,
+) -> anyhow::Result<String> {
+    let request = LlmRequest {
+        system: SUMMARY_SYSTEM.to_string(),
+        prompt: transcript,
+        sampling: Sampling {
+            temperature: 0.2,
+            max_tokens: 120,
+            ..Sampling::default()
+        },
+        ..LlmRequest::default()
+    };
+    let summary = unblock(backend, move |backend| {
+        backend.generate(&request, &mut |_| Ok(()))
+    })
+    .await?;
+
+    let text = summary.text.trim();
+    if text.is_empty() {
+        anyhow::bail!("empty summary of {}", conversation_id);
+    }
+    Ok(text.to_string())
+}
+
+/// Stores a memory of the owner of the conversation, embedded when the
+/// backend can
+async fn insert(
+    conn: &libsql::Connection,
+    backend: &Arc<dyn LlmBackend>,
+    conversation_id: &str,
+    text: &str,
+    through_turn: i64,
+) -> anyhow::Result<()> {
+    let to_embed = text.to_string();
+    let embedding = match unblock(backend, move |backend| backend.embed(&to_embed)).await {
+        Ok(embedding) => embedding.map(|e| serde_json::to_string(&e)).transpose()?,
+        Err(err) => {
+            warn!(
+                "memory will be recalled by keyword, cannot embed it: {:?}",
+                err
+            );
+            None
+        }
+    };
+
+    conn.execute(
+        "INSERT INTO npc_memories (npc, conversation_id, text, embedding, through_turn) VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            owner(conversation_id),
+            conversation_id,
+            text,
+            embedding,
+            through_turn,
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn keywords(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= MIN_KEYWORD_LEN)
+        .map(str::to_lowercase)
+        .filter(|word| !STOPWORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Share of the query keywords found in `text`, `0..=1`
+fn keyword_overlap(query: &HashSet<String>, text: &str) -> f32 {
+    if query.is_empty() {
+        return 0.;
+    }
+
+    let shared = keywords(text).intersection(query).count();
+    shared as f32 / query.len() as f32
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norms =
+        a.iter().map(|a| a * a).sum::<f32>().sqrt() * b.iter().map(|b| b * b).sum::<f32>().sqrt();
+
+    if norms == 0. { 0. } else { dot / norms }
+}
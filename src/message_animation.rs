@@ -1,70 +1,417 @@
-use crate::background::MessageChannel;
-use bevy::prelude::*;
+use crate::{
+    agent::Agent,
+    background::{DialogueCancelled, DialogueCompleted, DialogueFailed, DialogueToken, RequestId},
+    constants::*,
+};
+use bevy::{
+    prelude::*,
+    sprite::Anchor,
+    text::{TextBounds, TextLayoutInfo},
+};
 use std::{collections::HashMap, time::Duration};
 
+const BUBBLE_IMAGE: &str = "ui/bubble.png";
+/// Inset of the corners of `BUBBLE_IMAGE`, kept unscaled
+const BUBBLE_SLICE_BORDER: f32 = 8.;
+
+const BUBBLE_FONT_SIZE: f32 = 12.;
+const BUBBLE_TEXT_COLOR: Color = Color::srgb(0.16, 0.12, 0.1);
+/// Text wraps past this width, in world units
+const BUBBLE_MAX_WIDTH: f32 = 160.;
+const BUBBLE_PADDING: Vec2 = Vec2::new(6., 4.);
+
+/// Height of the bubble's bottom above the agent's feet
+const BUBBLE_OFFSET: f32 = 46.;
+/// Space left between bubbles pushed apart
+const BUBBLE_GAP: f32 = 2.;
+
+const TYPEWRITER_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Seconds a finished bubble stays readable: a base plus some per character
+const READ_SECONDS_BASE: f32 = 1.5;
+const READ_SECONDS_PER_CHAR: f32 = 0.06;
+const FADE_SECONDS: f32 = 0.5;
+
+/// Messages kept at once, the oldest expire early past it
+const MAX_MESSAGES: usize = 32;
+
 pub struct MessageAnimationPlugin;
 
 impl Plugin for MessageAnimationPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<MessageCollection>()
-            .add_systems(Update, (handle_message_stream, show_message_animated));
+            .init_resource::<MessageSettings>()
+            .add_observer(handle_message_stream)
+            .add_observer(on_dialogue_completed)
+            .add_observer(on_dialogue_failed)
+            .add_observer(on_dialogue_cancelled)
+            .add_observer(on_message_completed)
+            .add_systems(
+                Update,
+                (
+                    show_message_animated,
+                    expire_messages,
+                    fit_bubbles,
+                    layout_bubbles,
+                    fade_bubbles,
+                )
+                    .chain(),
+            );
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct MessageSettings {
+    /// Displayed messages expire after their read time, otherwise they stay
+    /// until pushed out by `max_messages`
+    pub auto_despawn: bool,
+    pub read_seconds_base: f32,
+    pub read_seconds_per_char: f32,
+    pub fade_seconds: f32,
+    pub max_messages: usize,
+}
+
+impl Default for MessageSettings {
+    fn default() -> Self {
+        Self {
+            auto_despawn: true,
+            read_seconds_base: READ_SECONDS_BASE,
+            read_seconds_per_char: READ_SECONDS_PER_CHAR,
+            fade_seconds: FADE_SECONDS,
+            max_messages: MAX_MESSAGES,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageState {
+    /// Tokens are still coming
+    Streaming,
+    /// The reply is over, the typewriter is catching up
+    Complete,
+    /// The whole text is shown
+    Displayed,
+    /// Fading out, despawned when done
+    Expired,
+}
+
+/// Fired when the whole text of a message is shown
+#[derive(Event, Debug)]
+pub struct MessageCompleted {
+    pub request: RequestId,
+    pub npc: Entity,
+    pub text: String,
+}
+
+/// Reply of an NPC, typed out in a speech bubble as it streams in
+pub struct Message {
+    pub npc: Entity,
+    pub state: MessageState,
+    bubble: Entity,
+    text: Entity,
+    full_text: Vec<char>,
+    shown: usize,
+    timer: Timer,
+    /// Read time while displayed, fade time once expired
+    expiry: Timer,
+}
+
+impl Message {
+    /// Part of the text the typewriter has shown so far
+    pub fn shown_text(&self) -> String {
+        self.full_text[..self.shown].iter().collect()
+    }
+
+    fn expire(&mut self, fade_seconds: f32) {
+        self.state = MessageState::Expired;
+        self.expiry = Timer::from_seconds(fade_seconds, TimerMode::Once);
     }
 }
 
 #[derive(Resource, Default)]
-pub struct MessageCollection(pub HashMap<usize, (Entity, usize, Vec<char>, Timer)>); // entity, index, full text
+pub struct MessageCollection(pub HashMap<RequestId, Message>);
+
+/// Background of a message, kept above its NPC
+#[derive(Component)]
+struct SpeechBubble {
+    npc: Entity,
+    request: RequestId,
+}
+
+/// Text of a `SpeechBubble`, its child
+#[derive(Component)]
+struct BubbleText;
 
 fn handle_message_stream(
+    msg: On<DialogueToken>,
     mut commands: Commands,
-    channel: Res<MessageChannel>,
     mut message_collection: ResMut<MessageCollection>,
     asset_server: Res<AssetServer>,
 ) {
-    for msg in channel.receiver.try_iter() {
-        if let Some((_, _, full_text, _)) = message_collection.0.get_mut(&msg.id) {
-            for c in msg.content.chars() {
-                full_text.push(c)
-            }
-        } else {
-            let entity = commands
-                .spawn((
-                    Text::new(""),
-                    TextFont {
-                        // This font is loaded and will be used instead of the default font.
-                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                        font_size: 67.0,
-                        ..default()
-                    },
-                    TextLayout::new_with_justify(Justify::Center),
-                ))
-                .id();
-
-            message_collection.0.insert(
-                msg.id,
-                (
-                    entity,
-                    0,
-                    msg.content.chars().collect(),
-                    Timer::new(Duration::from_millis(50), TimerMode::Repeating),
-                ),
-            );
-        }
+    if let Some(message) = message_collection.0.get_mut(&msg.request) {
+        message.full_text.extend(msg.text.chars());
+        return;
+    }
+
+    let text = commands
+        .spawn((
+            BubbleText,
+            Text2d::new(""),
+            TextFont {
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                font_size: BUBBLE_FONT_SIZE,
+                ..default()
+            },
+            TextColor(BUBBLE_TEXT_COLOR),
+            TextLayout::new_with_justify(Justify::Left),
+            TextBounds::new_horizontal(BUBBLE_MAX_WIDTH),
+            Anchor::BOTTOM_CENTER,
+            Transform::from_xyz(0., BUBBLE_PADDING.y, 0.1),
+        ))
+        .id();
+
+    let bubble = commands
+        .spawn((
+            SpeechBubble {
+                npc: msg.npc,
+                request: msg.request,
+            },
+            Sprite {
+                image: asset_server.load(BUBBLE_IMAGE),
+                image_mode: SpriteImageMode::Sliced(TextureSlicer {
+                    border: BorderRect::all(BUBBLE_SLICE_BORDER),
+                    ..default()
+                }),
+                // Sized by `fit_bubbles` once the text is laid out
+                custom_size: Some(Vec2::ZERO),
+                ..default()
+            },
+            Anchor::BOTTOM_CENTER,
+            Transform::from_xyz(0., 0., BUBBLE_Z_VALUE),
+        ))
+        .add_child(text)
+        .id();
+
+    message_collection.0.insert(
+        msg.request,
+        Message {
+            npc: msg.npc,
+            bubble,
+            text,
+            full_text: msg.text.chars().collect(),
+            shown: 0,
+            state: MessageState::Streaming,
+            timer: Timer::new(TYPEWRITER_INTERVAL, TimerMode::Repeating),
+            expiry: Timer::default(),
+        },
+    );
+}
+
+fn complete_message(message_collection: &mut MessageCollection, request: RequestId) {
+    if let Some(message) = message_collection.0.get_mut(&request)
+        && message.state == MessageState::Streaming
+    {
+        message.state = MessageState::Complete;
+    }
+}
+
+fn on_dialogue_completed(
+    completed: On<DialogueCompleted>,
+    mut message_collection: ResMut<MessageCollection>,
+    mut commands: Commands,
+) {
+    // An empty reply never got a bubble to type out
+    if !message_collection.0.contains_key(&completed.request) {
+        commands.trigger(MessageCompleted {
+            request: completed.request,
+            npc: completed.npc,
+            text: String::new(),
+        });
+        return;
     }
+
+    complete_message(&mut message_collection, completed.request);
+}
+
+fn on_dialogue_failed(
+    failed: On<DialogueFailed>,
+    mut message_collection: ResMut<MessageCollection>,
+) {
+    complete_message(&mut message_collection, failed.request);
+}
+
+fn on_dialogue_cancelled(
+    cancelled: On<DialogueCancelled>,
+    mut message_collection: ResMut<MessageCollection>,
+) {
+    complete_message(&mut message_collection, cancelled.request);
+}
+
+fn on_message_completed(completed: On<MessageCompleted>) {
+    debug!(
+        "{:?} finished saying {:?} (request {})",
+        completed.npc, completed.text, completed.request
+    );
 }
 
 fn show_message_animated(
     mut message_collection: ResMut<MessageCollection>,
-    mut query: Query<&mut Text>,
+    mut query: Query<&mut Text2d>,
+    settings: Res<MessageSettings>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (request, message) in &mut message_collection.0 {
+        if message.shown < message.full_text.len()
+            && message.timer.tick(time.delta()).just_finished()
+            && let Ok(mut text) = query.get_mut(message.text)
+        {
+            text.0.push(message.full_text[message.shown]);
+            message.shown += 1;
+        }
+
+        if message.state == MessageState::Complete && message.shown == message.full_text.len() {
+            message.state = MessageState::Displayed;
+
+            let read_seconds = settings.read_seconds_base
+                + settings.read_seconds_per_char * message.full_text.len() as f32;
+            message.expiry = Timer::from_seconds(read_seconds, TimerMode::Once);
+
+            commands.trigger(MessageCompleted {
+                request: *request,
+                npc: message.npc,
+                text: message.full_text.iter().collect(),
+            });
+        }
+    }
+}
+
+/// Expires read messages, the oldest ones past `max_messages` and those of
+/// despawned NPCs
+fn expire_messages(
+    mut message_collection: ResMut<MessageCollection>,
+    settings: Res<MessageSettings>,
+    agents_q: Query<(), With<Agent>>,
     time: Res<Time>,
 ) {
-    for (_, (entity, idx, full_text, timer)) in &mut message_collection.0 {
-        if timer.tick(time.delta()).just_finished() {
-            if let Ok(mut text) = query.get_mut(*entity) {
-                if let Some(next_char) = full_text.get(*idx) {
-                    text.0.push(next_char.clone());
-                    *idx += 1;
-                }
+    for message in message_collection.0.values_mut() {
+        if !agents_q.contains(message.npc) {
+            message.expire(0.);
+        } else if settings.auto_despawn
+            && message.state == MessageState::Displayed
+            && message.expiry.tick(time.delta()).is_finished()
+        {
+            message.expire(settings.fade_seconds);
+        }
+    }
+
+    let mut live: Vec<RequestId> = message_collection
+        .0
+        .iter()
+        .filter(|(_, message)| message.state != MessageState::Expired)
+        .map(|(request, _)| *request)
+        .collect();
+
+    if live.len() > settings.max_messages {
+        live.sort();
+        for request in &live[..live.len() - settings.max_messages] {
+            if let Some(message) = message_collection.0.get_mut(request) {
+                message.expire(settings.fade_seconds);
             }
         }
     }
 }
+
+type LaidOutText = (&'static TextLayoutInfo, &'static ChildOf);
+
+/// Sizes bubbles around their wrapped text
+fn fit_bubbles(
+    text_q: Query<LaidOutText, (With<BubbleText>, Changed<TextLayoutInfo>)>,
+    mut bubbles_q: Query<&mut Sprite, With<SpeechBubble>>,
+) {
+    for (layout, child_of) in &text_q {
+        let Ok(mut sprite) = bubbles_q.get_mut(child_of.parent()) else {
+            continue;
+        };
+
+        let size = if layout.size == Vec2::ZERO {
+            Vec2::ZERO
+        } else {
+            (layout.size + 2. * BUBBLE_PADDING).max(Vec2::splat(2. * BUBBLE_SLICE_BORDER))
+        };
+        sprite.custom_size = Some(size);
+    }
+}
+
+/// Places bubbles above their NPC, oldest first. A bubble overlapping one
+/// already placed is pushed above it, which stacks the messages of an NPC and
+/// keeps NPCs talking side by side readable.
+fn layout_bubbles(
+    mut bubbles_q: Query<(&SpeechBubble, &Sprite, &mut Transform)>,
+    agents_q: Query<&Transform, Without<SpeechBubble>>,
+) {
+    let mut bubbles: Vec<_> = bubbles_q.iter_mut().collect();
+    bubbles.sort_by_key(|(bubble, ..)| bubble.request);
+
+    let mut placed: Vec<Rect> = Vec::with_capacity(bubbles.len());
+
+    for (bubble, sprite, mut transform) in bubbles {
+        let Ok(agent_transform) = agents_q.get(bubble.npc) else {
+            continue;
+        };
+
+        let size = sprite.custom_size.unwrap_or_default();
+        let bottom_center = agent_transform.translation.truncate() + Vec2::Y * BUBBLE_OFFSET;
+        let mut rect = Rect::from_center_size(bottom_center + Vec2::Y * size.y / 2., size);
+
+        while let Some(top) = placed
+            .iter()
+            .filter(|other| !other.intersect(rect).is_empty())
+            .map(|other| other.max.y)
+            .reduce(f32::max)
+        {
+            let lift = top + BUBBLE_GAP - rect.min.y;
+            rect.min.y += lift;
+            rect.max.y += lift;
+        }
+
+        if size != Vec2::ZERO {
+            placed.push(rect);
+        }
+
+        let translation = Vec3::new(rect.center().x, rect.min.y, BUBBLE_Z_VALUE);
+        if transform.translation != translation {
+            transform.translation = translation;
+        }
+    }
+}
+
+/// Fades expired bubbles out, then despawns them and forgets their message
+fn fade_bubbles(
+    mut message_collection: ResMut<MessageCollection>,
+    mut bubbles_q: Query<&mut Sprite, With<SpeechBubble>>,
+    mut text_q: Query<&mut TextColor, With<BubbleText>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    message_collection.0.retain(|_, message| {
+        if message.state != MessageState::Expired {
+            return true;
+        }
+
+        if message.expiry.tick(time.delta()).is_finished() {
+            commands.entity(message.bubble).despawn();
+            return false;
+        }
+
+        let alpha = message.expiry.fraction_remaining();
+        if let Ok(mut sprite) = bubbles_q.get_mut(message.bubble) {
+            sprite.color.set_alpha(alpha);
+        }
+        if let Ok(mut text_color) = text_q.get_mut(message.text) {
+            text_color.0.set_alpha(alpha);
+        }
+
+        true
+    });
+}
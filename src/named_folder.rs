@@ -0,0 +1,67 @@
+use std::{collections::HashMap, path::Path};
+
+use bevy::{asset::LoadedFolder, prelude::*};
+
+/// Name of an asset file: its file name without `.{extension}`
+pub fn asset_name<'a>(path: &'a Path, extension: &str) -> Option<&'a str> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(&format!(".{}", extension))
+}
+
+/// Folder of assets named after their files, which the animation, schedule
+/// and persona libraries are built from
+#[derive(Default)]
+pub struct NamedFolder {
+    handle: Handle<LoadedFolder>,
+    /// Read from the loaded folder at least once
+    ready: bool,
+}
+
+impl NamedFolder {
+    pub fn load(asset_server: &AssetServer, dir: &'static str) -> Self {
+        Self {
+            handle: asset_server.load_folder(dir),
+            ready: false,
+        }
+    }
+
+    /// Whether one of `events` is the folder loading or its files changing
+    pub fn is_changed(&self, events: &mut MessageReader<AssetEvent<LoadedFolder>>) -> bool {
+        events.read().fold(false, |changed, event| {
+            changed
+                || event.is_loaded_with_dependencies(&self.handle)
+                || event.is_modified(&self.handle)
+        })
+    }
+
+    /// Whether the folder was read, or cannot be loaded
+    pub fn is_settled(&self, asset_server: &AssetServer) -> bool {
+        self.ready
+            || asset_server
+                .recursive_dependency_load_state(&self.handle)
+                .is_failed()
+    }
+
+    /// Handles of the `*.{extension}` files of the folder by name, `None`
+    /// until it is loaded
+    pub fn read<A: Asset>(
+        &mut self,
+        folders: &Assets<LoadedFolder>,
+        extension: &str,
+    ) -> Option<HashMap<String, Handle<A>>> {
+        let folder = folders.get(&self.handle)?;
+        self.ready = true;
+
+        Some(
+            folder
+                .handles
+                .iter()
+                .filter_map(|handle| {
+                    let name = asset_name(handle.path()?.path(), extension)?;
+                    Some((name.to_string(), handle.clone().try_typed::<A>().ok()?))
+                })
+                .collect(),
+        )
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::{HashSet, VecDeque};
 use bevy::prelude::*;
 
 use crate::{
-    constants::{GRID_HEIGHT, GRID_WIDTH, PATHFINDER_MAX_DEPTH},
+    constants::PATHFINDER_MAX_DEPTH,
     world::{components::*, spatial_idx::*},
 };
 
@@ -80,14 +80,6 @@ impl Pathfinder {
                     continue;
                 }
 
-                if new_x < 0 || new_y < 0 {
-                    continue;
-                }
-
-                if new_x > (GRID_WIDTH - 1) || new_y > (GRID_HEIGHT - 1) {
-                    continue;
-                }
-
                 nearby.push(GridPosition { x: new_x, y: new_y });
             }
         }
@@ -160,10 +152,13 @@ impl Pathfinder {
 
             // Static Check
             let current_tile_data = spatial_index
-                .map
-                .get(&(current.position.x, current.position.y))
+                .get(current.position.x, current.position.y)
                 .unwrap();
-            let neighbor_tile_data = spatial_index.map.get(&(pos.x, pos.y)).unwrap();
+
+            // neighbor chunk not loaded
+            let Some(neighbor_tile_data) = spatial_index.get(pos.x, pos.y) else {
+                continue;
+            };
 
             if !current_tile_data.is_traversable_to(neighbor_tile_data) {
                 continue;
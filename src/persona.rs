@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedFolder, io::Reader},
+    prelude::*,
+};
+use rand::seq::IteratorRandom;
+use serde::Deserialize;
+
+use crate::{
+    agent::Agent,
+    background::ConversationId,
+    named_folder::{NamedFolder, asset_name},
+    schedule::{Schedule, ScheduleLibrary, assign_archetypes},
+};
+
+/// Every `*.persona.json` file in this folder is an `NpcPersona`, named after the file
+const PERSONAS_DIR: &str = "personas";
+const PERSONA_EXTENSION: &str = "persona.json";
+
+/// Opening line of every system prompt
+const SETTING: &str = "You are an NPC in a medieval fantasy RPG. You always speak in character.";
+
+/// Rules every NPC follows, before its own
+const COMMON_RULES: [&str; 5] = [
+    "Speak in first person",
+    "Medieval tone",
+    "No modern words",
+    "No explanations",
+    "Max 2 sentences",
+];
+
+/// Phrases no NPC uses, before its own
+const COMMON_AVOID: [&str; 4] = ["I can help", "Perhaps", "It seems", "As a"];
+
+const CLOSING: &str = "IMPORTANT:
+Respond with ONLY the NPC's spoken dialogue.
+Do NOT describe actions.
+Do NOT explain your reasoning.";
+
+pub struct PersonaPlugin;
+
+impl Plugin for PersonaPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<NpcPersona>()
+            .init_asset_loader::<NpcPersonaLoader>()
+            .init_resource::<PersonaLibrary>()
+            .add_systems(Startup, load_personas)
+            .add_systems(
+                Update,
+                (update_persona_library, assign_personas)
+                    .chain()
+                    .after(assign_archetypes),
+            );
+    }
+}
+
+/// Who an NPC is, rendered into the system prompt of its conversations
+#[derive(Component, Asset, TypePath, Deserialize, Clone, Debug)]
+pub struct NpcPersona {
+    /// Name of the persona's file, which names its conversations
+    #[serde(skip)]
+    pub id: String,
+    pub name: String,
+    pub role: String,
+    pub personality: Vec<String>,
+    pub loyalty: String,
+    pub mood: String,
+    /// Things the NPC knows about the world
+    #[serde(default)]
+    pub facts: Vec<String>,
+    /// Speaking rules on top of `COMMON_RULES`
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// Phrases to avoid on top of `COMMON_AVOID`
+    #[serde(default)]
+    pub avoid: Vec<String>,
+    /// Schedule archetype the persona is given to first
+    #[serde(default)]
+    pub archetype: Option<String>,
+}
+
+impl Default for NpcPersona {
+    fn default() -> Self {
+        Self {
+            id: "stranger".to_string(),
+            name: "Stranger".to_string(),
+            role: "Villager".to_string(),
+            personality: vec!["Reserved".to_string(), "Wary".to_string()],
+            loyalty: "Nobody".to_string(),
+            mood: "Neutral".to_string(),
+            facts: vec![],
+            rules: vec![],
+            avoid: vec![],
+            archetype: None,
+        }
+    }
+}
+
+impl NpcPersona {
+    /// System prompt of the persona in its `situation`, independent of the
+    /// model's template
+    pub fn system_prompt(&self, situation: &[String]) -> String {
+        let mut prompt = format!(
+            "{}\n\nNPC PROFILE:\nName: {}\nRole: {}\nPersonality: {}\nLoyalty: {}\nMood: {}\n",
+            SETTING,
+            self.name,
+            self.role,
+            self.personality.join(", "),
+            self.loyalty,
+            self.mood
+        );
+
+        if !self.facts.is_empty() {
+            prompt.push_str("\nKNOWN FACTS:\n");
+            for fact in &self.facts {
+                prompt.push_str(&format!("- {}\n", fact));
+            }
+        }
+
+        if !situation.is_empty() {
+            prompt.push('\n');
+            prompt.push_str(&situation_prompt(situation));
+        }
+
+        prompt.push_str("\nRULES:\n");
+        for rule in COMMON_RULES
+            .iter()
+            .copied()
+            .chain(self.rules.iter().map(String::as_str))
+        {
+            prompt.push_str(&format!("- {}\n", rule));
+        }
+
+        prompt.push_str("\nAVOID:\n");
+        for phrase in COMMON_AVOID
+            .iter()
+            .copied()
+            .chain(self.avoid.iter().map(String::as_str))
+        {
+            prompt.push_str(&format!("- '{}'\n", phrase));
+        }
+
+        prompt.push('\n');
+        prompt.push_str(CLOSING);
+        prompt
+    }
+}
+
+/// What is going on around the NPC, as laid out in its system prompt
+pub fn situation_prompt(situation: &[String]) -> String {
+    if situation.is_empty() {
+        return String::new();
+    }
+
+    let mut prompt = "RIGHT NOW:\n".to_string();
+    for fact in situation {
+        prompt.push_str(&format!("- {}\n", fact));
+    }
+    prompt
+}
+
+#[derive(Default, TypePath)]
+pub struct NpcPersonaLoader;
+
+impl AssetLoader for NpcPersonaLoader {
+    type Asset = NpcPersona;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<NpcPersona, anyhow::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let mut persona = serde_json::from_slice::<NpcPersona>(&bytes)?;
+        persona.id = asset_name(load_context.path(), PERSONA_EXTENSION)
+            .ok_or_else(|| anyhow::anyhow!("persona file has no name"))?
+            .to_string();
+
+        Ok(persona)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[PERSONA_EXTENSION]
+    }
+}
+
+/// Personas loaded from `PERSONAS_DIR`, by ID
+#[derive(Resource, Default)]
+pub struct PersonaLibrary {
+    folder: NamedFolder,
+    personas: HashMap<String, NpcPersona>,
+}
+
+impl PersonaLibrary {
+    /// Whether agents can get their persona, the default one when the folder
+    /// cannot be loaded
+    fn is_settled(&self, asset_server: &AssetServer) -> bool {
+        self.folder.is_settled(asset_server)
+    }
+}
+
+fn load_personas(mut library: ResMut<PersonaLibrary>, asset_server: Res<AssetServer>) {
+    library.folder = NamedFolder::load(&asset_server, PERSONAS_DIR);
+}
+
+/// Rebuilds the library when the personas folder loads or one of its files
+/// changes. Agents pick up the changes of their persona.
+fn update_persona_library(
+    mut folder_events: MessageReader<AssetEvent<LoadedFolder>>,
+    mut persona_events: MessageReader<AssetEvent<NpcPersona>>,
+    mut library: ResMut<PersonaLibrary>,
+    folders: Res<Assets<LoadedFolder>>,
+    personas: Res<Assets<NpcPersona>>,
+    mut personas_q: Query<&mut NpcPersona>,
+) {
+    let folder_changed = library.folder.is_changed(&mut folder_events);
+    let persona_changed = persona_events.read().any(|event| {
+        matches!(
+            event,
+            AssetEvent::Modified { .. } | AssetEvent::Removed { .. }
+        )
+    });
+
+    if !folder_changed && !persona_changed {
+        return;
+    }
+
+    let Some(handles) = library
+        .folder
+        .read::<NpcPersona>(&folders, PERSONA_EXTENSION)
+    else {
+        return;
+    };
+
+    let loaded: HashMap<String, NpcPersona> = handles
+        .into_iter()
+        .filter_map(|(id, handle)| Some((id, personas.get(&handle)?.clone())))
+        .collect();
+
+    info!("personas: {:?}", loaded.keys().collect::<Vec<_>>());
+
+    for mut persona in &mut personas_q {
+        if let Some(updated) = loaded.get(&persona.id) {
+            *persona = updated.clone();
+        }
+    }
+
+    library.personas = loaded;
+}
+
+type UnnamedAgent = (Entity, Option<&'static Schedule>);
+
+/// Gives agents a persona, preferring ones made for their archetype and not
+/// used by another agent yet. Their conversations are named after it, so
+/// they survive restarts.
+fn assign_personas(
+    query: Query<UnnamedAgent, (With<Agent>, Without<NpcPersona>)>,
+    conversations_q: Query<&ConversationId>,
+    personas_q: Query<&NpcPersona>,
+    library: Res<PersonaLibrary>,
+    schedules: Res<ScheduleLibrary>,
+    asset_server: Res<AssetServer>,
+    mut commands: Commands,
+) {
+    if query.is_empty()
+        || !library.is_settled(&asset_server)
+        || !schedules.is_settled(&asset_server)
+    {
+        return;
+    }
+
+    let mut taken: Vec<String> = personas_q
+        .iter()
+        .map(|persona| persona.id.clone())
+        .collect();
+    let mut conversations: Vec<String> = conversations_q
+        .iter()
+        .map(|conversation| conversation.0.clone())
+        .collect();
+
+    for (entity, schedule) in &query {
+        let archetype = schedule.map(|schedule| schedule.archetype.as_str());
+
+        let made_for = |persona: &NpcPersona| {
+            persona.archetype.is_some() && persona.archetype.as_deref() == archetype
+        };
+        let free = |persona: &NpcPersona| !taken.contains(&persona.id);
+        let pick = |filter: &dyn Fn(&NpcPersona) -> bool| {
+            library
+                .personas
+                .values()
+                .filter(|persona| filter(persona))
+                .choose(&mut rand::thread_rng())
+                .cloned()
+        };
+
+        let persona = pick(&|persona| made_for(persona) && free(persona))
+            .or_else(|| pick(&|persona| persona.archetype.is_none() && free(persona)))
+            .or_else(|| pick(&|persona| made_for(persona) || persona.archetype.is_none()))
+            .unwrap_or_default();
+
+        // Agents sharing a persona keep apart conversations
+        let conversation_id = (1..)
+            .map(|n| match n {
+                1 => persona.id.clone(),
+                n => format!("{}-{}", persona.id, n),
+            })
+            .find(|id| !conversations.contains(id))
+            .expect("conversation IDs are unbounded");
+
+        debug!(
+            "{:?} is {} the {} ({})",
+            entity, persona.name, persona.role, conversation_id
+        );
+        taken.push(persona.id.clone());
+        conversations.push(conversation_id.clone());
+        commands
+            .entity(entity)
+            .insert((persona, ConversationId(conversation_id)));
+    }
+}
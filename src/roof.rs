@@ -1,22 +1,84 @@
+use std::collections::{HashMap, HashSet};
+
 use bevy::prelude::*;
-use bevy_ecs_tilemap::{
-    map::TilemapId,
-    tiles::{TileColor, TilePos},
-};
+use bevy_ecs_tilemap::tiles::TileColor;
 
 use crate::{
-    agent::Agent,
-    world::{components::*, spatial_idx::*},
+    agent::{Agent, SelectedAgent},
+    camera::CameraFollow,
+    world::{
+        agent_idx::AgentIndex,
+        components::*,
+        grid::CursorTile,
+        rooms::{Building, RoomIndex},
+        spatial_idx::*,
+    },
 };
 
+/// Time for a building to go from opaque to fully revealed, and back
+const ROOF_FADE_SECONDS: f32 = 0.4;
+
 pub struct RoofPlugin;
 
 impl Plugin for RoofPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, roof_opacity_system);
+        app.init_resource::<RoofRevealPolicy>()
+            .add_observer(on_add_building)
+            .add_systems(
+                Update,
+                (
+                    cycle_roof_reveal_policy,
+                    assign_roof_revealers,
+                    update_roof_reveal,
+                    roof_opacity_system,
+                    indoor_agents_visibility,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Which agents get `RevealsRoofs`
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoofRevealPolicy {
+    /// The agent the player selected
+    #[default]
+    Controlled,
+    /// The agent the camera follows
+    CameraFollowed,
+    /// Agents on the tile under the mouse cursor
+    UnderCursor,
+    AllAgents,
+}
+
+impl RoofRevealPolicy {
+    fn next(self) -> Self {
+        match self {
+            RoofRevealPolicy::Controlled => RoofRevealPolicy::CameraFollowed,
+            RoofRevealPolicy::CameraFollowed => RoofRevealPolicy::UnderCursor,
+            RoofRevealPolicy::UnderCursor => RoofRevealPolicy::AllAgents,
+            RoofRevealPolicy::AllAgents => RoofRevealPolicy::Controlled,
+        }
     }
 }
 
+/// Reveals the building the entity stands in. `radius` limits the reveal to the
+/// tiles around the entity and `opacity` replaces the `TransparencyStrategy` one.
+#[derive(Component, Default, Clone, Debug)]
+pub struct RevealsRoofs {
+    pub radius: Option<i32>,
+    pub opacity: Option<f32>,
+}
+
+/// How far a building is revealed: 0 is a fully opaque roof, 1 fully revealed
+#[derive(Component, Default, Debug, PartialEq)]
+pub struct RoofReveal {
+    pub amount: f32,
+    /// Revealed tiles, `None` for the whole building
+    pub area: Option<HashSet<(i32, i32)>>,
+    pub opacity: Option<f32>,
+}
+
 // Strategy enum for handling different kinds of roof/interior tiles
 enum TransparencyStrategy {
     Roof,
@@ -30,114 +92,215 @@ impl TransparencyStrategy {
             TransparencyStrategy::Indoor => 0.1,
         }
     }
-
-    fn radius(&self) -> i32 {
-        match self {
-            TransparencyStrategy::Roof => 4,
-            TransparencyStrategy::Indoor => 6,
-        }
-    }
-
-    fn should_affect_neighbor(&self, tile: &TileData) -> bool {
-        match self {
-            TransparencyStrategy::Roof => tile.is_roof(),
-            TransparencyStrategy::Indoor => tile.is_indoor(),
-        }
-    }
-
-    fn make_adjacent_walls_transparent(&self) -> bool {
-        match self {
-            TransparencyStrategy::Roof => false,
-            TransparencyStrategy::Indoor => true,
-        }
-    }
 }
 
 fn can_be_transparent(tile_data: &TileData) -> Option<TransparencyStrategy> {
     if tile_data.is_roof() {
         return Some(TransparencyStrategy::Roof);
     }
-    if tile_data.is_indoor() {
+    // Walls are hidden together with the interior they enclose
+    if tile_data.is_indoor() || tile_data.is_wall() {
         return Some(TransparencyStrategy::Indoor);
     }
     None
 }
 
-fn roof_opacity_system(
-    agents_q: Query<&GridPosition, With<Agent>>,
-    mut tiles_q: Query<(&TilemapId, &TilePos, &mut TileColor)>,
-    spatial_idx: Res<SpatialIndex>,
+fn on_add_building(add: On<Add, Building>, mut commands: Commands) {
+    commands.entity(add.entity).insert(RoofReveal::default());
+}
+
+fn cycle_roof_reveal_policy(
+    input: Res<ButtonInput<KeyCode>>,
+    mut policy: ResMut<RoofRevealPolicy>,
 ) {
-    const OPAQUE_ALPHA: f32 = 1.0;
+    if input.just_pressed(KeyCode::KeyR) {
+        *policy = policy.next();
+        info!("roof reveal policy: {:?}", *policy);
+    }
+}
 
-    let mut transparent_tiles: std::collections::HashMap<(i32, i32), f32> =
-        std::collections::HashMap::new();
-
-    let mut zones_requiring_wall_transparency: std::collections::HashSet<(i32, i32)> =
-        std::collections::HashSet::new();
-
-    // 1. Determine base transparency for tiles under roofs/insides based on agent positions.
-    for agent_pos in &agents_q {
-        if let Some(tile_data) = spatial_idx.get_entity_data(agent_pos.x, agent_pos.y) {
-            if let Some(strategy) = can_be_transparent(&tile_data) {
-                let opacity = strategy.opacity();
-                let radius = strategy.radius();
-
-                for x in (agent_pos.x.saturating_sub(radius))..=(agent_pos.x + radius) {
-                    for y in (agent_pos.y.saturating_sub(radius))..=(agent_pos.y + radius) {
-                        if let Some(neighbor_tile_data) = spatial_idx.get_entity_data(x, y) {
-                            if strategy.should_affect_neighbor(&neighbor_tile_data) {
-                                let pos = (x, y);
-                                let current_alpha =
-                                    transparent_tiles.entry(pos).or_insert(OPAQUE_ALPHA);
-                                *current_alpha = current_alpha.min(opacity);
-
-                                if strategy.make_adjacent_walls_transparent() {
-                                    zones_requiring_wall_transparency.insert(pos);
-                                }
-                            }
-                        }
-                    }
-                }
+/// Keeps `RevealsRoofs` on the agents chosen by the `RoofRevealPolicy`
+fn assign_roof_revealers(
+    policy: Res<RoofRevealPolicy>,
+    selected: Res<SelectedAgent>,
+    camera_follow: Res<CameraFollow>,
+    cursor_tile: Res<CursorTile>,
+    agent_idx: Res<AgentIndex>,
+    agents_q: Query<(Entity, Has<RevealsRoofs>), With<Agent>>,
+    mut commands: Commands,
+) {
+    let revealers: HashSet<Entity> = match *policy {
+        RoofRevealPolicy::Controlled => selected.0.into_iter().collect(),
+        RoofRevealPolicy::CameraFollowed => camera_follow.0.into_iter().collect(),
+        RoofRevealPolicy::UnderCursor => cursor_tile
+            .0
+            .iter()
+            .flat_map(|tile| agent_idx.agents_in_radius(tile, 0))
+            .map(|(entity, _)| entity)
+            .collect(),
+        RoofRevealPolicy::AllAgents => agents_q.iter().map(|(entity, _)| entity).collect(),
+    };
+
+    for (entity, reveals_roofs) in &agents_q {
+        let should_reveal = revealers.contains(&entity);
+
+        if should_reveal && !reveals_roofs {
+            commands.entity(entity).insert(RevealsRoofs::default());
+        } else if !should_reveal && reveals_roofs {
+            commands.entity(entity).remove::<RevealsRoofs>();
+        }
+    }
+}
+
+#[derive(Default)]
+struct BuildingRevealRequest {
+    whole: bool,
+    area: HashSet<(i32, i32)>,
+    opacity: Option<f32>,
+}
+
+/// Fades in the buildings occupied by a `RevealsRoofs` entity and fades out the others
+fn update_roof_reveal(
+    revealers_q: Query<(&GridPosition, &RevealsRoofs)>,
+    room_idx: Res<RoomIndex>,
+    mut buildings_q: Query<(Entity, &Building, &mut RoofReveal)>,
+    time: Res<Time>,
+) {
+    let mut requests: HashMap<Entity, BuildingRevealRequest> = HashMap::new();
+
+    for (position, reveals_roofs) in &revealers_q {
+        let Some(building_entity) = room_idx.building_at(position.x, position.y) else {
+            continue;
+        };
+
+        let Ok((_, building, _)) = buildings_q.get(building_entity) else {
+            continue;
+        };
+
+        let request = requests.entry(building_entity).or_default();
+
+        match reveals_roofs.radius {
+            Some(radius) => {
+                request.area.extend(building.tiles.iter().filter(|(x, y)| {
+                    (x - position.x).pow(2) + (y - position.y).pow(2) <= radius.pow(2)
+                }));
             }
+            None => request.whole = true,
+        }
+
+        if let Some(opacity) = reveals_roofs.opacity {
+            request.opacity = Some(request.opacity.map_or(opacity, |o| o.min(opacity)));
         }
     }
 
-    // 2. Find adjacent walls for zones that require it and set their transparency.
-    let inside_opacity = TransparencyStrategy::Indoor.opacity();
-    for &(x, y) in &zones_requiring_wall_transparency {
-        for nx in (x - 1)..=(x + 1) {
-            for ny in (y - 1)..=(y + 1) {
-                if nx == x && ny == y {
-                    continue;
-                }
+    let step = time.delta_secs() / ROOF_FADE_SECONDS;
+
+    for (building_entity, _, mut reveal) in &mut buildings_q {
+        let target = match requests.remove(&building_entity) {
+            Some(request) => {
+                // Whole building wins over any radius
+                let area = (!request.whole).then_some(request.area);
 
-                if let Some(tile_data) = spatial_idx.get_entity_data(nx, ny) {
-                    if tile_data.is_wall() {
-                        let wall_pos = (nx, ny);
-                        let current_alpha =
-                            transparent_tiles.entry(wall_pos).or_insert(OPAQUE_ALPHA);
-                        *current_alpha = current_alpha.min(inside_opacity);
-                    }
+                // Fading out keeps the last revealed area
+                if reveal.area != area || reveal.opacity != request.opacity {
+                    reveal.area = area;
+                    reveal.opacity = request.opacity;
                 }
+                1.0
             }
+            None => 0.0,
+        };
+
+        if reveal.amount != target {
+            reveal.amount = if reveal.amount < target {
+                (reveal.amount + step).min(target)
+            } else {
+                (reveal.amount - step).max(target)
+            };
         }
     }
+}
 
-    // 3. Update opacity for all relevant tiles.
-    for (tilemap_id, tile_pos, mut tile_color) in &mut tiles_q {
-        let pos = (tile_pos.x as i32, tile_pos.y as i32);
+/// Applies the reveal amount of the buildings that changed to their tiles
+fn roof_opacity_system(
+    buildings_q: Query<(&Building, &RoofReveal), Changed<RoofReveal>>,
+    mut tiles_q: Query<&mut TileColor>,
+    spatial_idx: Res<SpatialIndex>,
+) {
+    const OPAQUE_ALPHA: f32 = 1.0;
+
+    for (building, reveal) in &buildings_q {
+        for &(x, y) in &building.tiles {
+            let Some(tile_data) = spatial_idx.get(x, y) else {
+                continue;
+            };
 
-        if let Some(tile_data) = spatial_idx.get_entity_data(pos.0, pos.1) {
-            if tile_data.is_building() {
-                if Some(tilemap_id.0) == tile_data.tilemap_entity {
-                    let target_alpha = *transparent_tiles.get(&pos).unwrap_or(&OPAQUE_ALPHA);
+            let Some(strategy) = can_be_transparent(tile_data) else {
+                continue;
+            };
 
-                    if tile_color.0.alpha() != target_alpha {
-                        tile_color.0.set_alpha(target_alpha);
-                    }
+            if let Some(mut tile_color) = tile_data
+                .tile_entity
+                .and_then(|entity| tiles_q.get_mut(entity).ok())
+            {
+                let revealed = reveal
+                    .area
+                    .as_ref()
+                    .is_none_or(|area| area.contains(&(x, y)));
+
+                let target_alpha = if revealed {
+                    let opacity = reveal.opacity.unwrap_or(strategy.opacity());
+                    OPAQUE_ALPHA.lerp(opacity, reveal.amount)
+                } else {
+                    OPAQUE_ALPHA
+                };
+
+                if tile_color.0.alpha() != target_alpha {
+                    tile_color.0.set_alpha(target_alpha);
                 }
             }
         }
     }
 }
+
+/// Agents indoors fade in and out with the roof covering them, so they are not
+/// drawn on top of an opaque roof
+fn indoor_agents_visibility(
+    mut agents_q: Query<(&GridPosition, &mut Sprite, &mut Visibility), With<Agent>>,
+    buildings_q: Query<&RoofReveal>,
+    spatial_idx: Res<SpatialIndex>,
+    room_idx: Res<RoomIndex>,
+) {
+    for (position, mut sprite, mut visibility) in &mut agents_q {
+        let is_indoor = spatial_idx
+            .get(position.x, position.y)
+            .is_some_and(|tile_data| tile_data.is_indoor());
+
+        let alpha = if is_indoor {
+            room_idx
+                .building_at(position.x, position.y)
+                .and_then(|building_entity| buildings_q.get(building_entity).ok())
+                .map_or(1.0, |reveal| {
+                    let revealed = reveal
+                        .area
+                        .as_ref()
+                        .is_none_or(|area| area.contains(&(position.x, position.y)));
+
+                    if revealed { reveal.amount } else { 0.0 }
+                })
+        } else {
+            1.0
+        };
+
+        if sprite.color.alpha() != alpha {
+            sprite.color.set_alpha(alpha);
+        }
+
+        let target_visibility = if alpha > 0.0 {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        visibility.set_if_neq(target_visibility);
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedFolder, io::Reader},
+    ecs::system::SystemParam,
+    prelude::*,
+};
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::Deserialize;
+
+use crate::{
+    agent::{Agent, Walking},
+    animation::AnimationController,
+    clock::GameClock,
+    named_folder::NamedFolder,
+    world::{
+        components::*,
+        rooms::{Room, RoomIndex},
+        spatial_idx::*,
+    },
+};
+
+/// Every `*.schedule.json` file in this folder is an `ArchetypeSchedule`, named after the file
+const SCHEDULES_DIR: &str = "schedules";
+const SCHEDULE_EXTENSION: &str = "schedule.json";
+
+pub struct SchedulePlugin;
+
+impl Plugin for SchedulePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<ArchetypeSchedule>()
+            .init_asset_loader::<ArchetypeScheduleLoader>()
+            .init_resource::<ScheduleLibrary>()
+            .add_observer(on_resume_schedule)
+            .add_systems(Startup, load_schedules)
+            .add_systems(
+                Update,
+                (
+                    update_schedule_library,
+                    assign_archetypes,
+                    assign_schedule_places,
+                    update_schedules,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Where a schedule block takes place
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Place {
+    Home,
+    Work,
+    /// Room named in the level's `Rooms` field
+    Room(String),
+    Anywhere,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Activity {
+    pub name: String,
+    pub minutes: f64,
+    /// Animation clip played during the activity
+    #[serde(default)]
+    pub clip: Option<String>,
+    /// Walk to another tile of the place before starting
+    #[serde(default)]
+    pub roam: bool,
+}
+
+/// Activities repeated at `place` from `from` o'clock until the next block
+#[derive(Deserialize, Clone, Debug)]
+pub struct ScheduleBlock {
+    pub from: u32,
+    pub place: Place,
+    pub activities: Vec<Activity>,
+}
+
+/// Daily routine shared by the NPCs of an archetype
+#[derive(Asset, TypePath, Deserialize, Clone, Debug)]
+pub struct ArchetypeSchedule {
+    /// Name of the room the archetype works in, any room when missing
+    #[serde(default)]
+    pub work: Option<String>,
+    pub blocks: Vec<ScheduleBlock>,
+}
+
+impl ArchetypeSchedule {
+    /// Block running at `hour`, the last one of the day before the first starts
+    fn block_at(&self, hour: u32) -> Option<usize> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.from <= hour)
+            .max_by_key(|(_, block)| block.from)
+            .or_else(|| {
+                self.blocks
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, block)| block.from)
+            })
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct ArchetypeScheduleLoader;
+
+impl AssetLoader for ArchetypeScheduleLoader {
+    type Asset = ArchetypeSchedule;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<ArchetypeSchedule, anyhow::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let schedule = serde_json::from_slice::<ArchetypeSchedule>(&bytes)?;
+        if schedule.blocks.is_empty() {
+            anyhow::bail!("schedule has no blocks");
+        }
+
+        Ok(schedule)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[SCHEDULE_EXTENSION]
+    }
+}
+
+/// Archetype schedules loaded from `SCHEDULES_DIR`, by name
+#[derive(Resource, Default)]
+pub struct ScheduleLibrary {
+    folder: NamedFolder,
+    schedules: HashMap<String, ArchetypeSchedule>,
+}
+
+impl ScheduleLibrary {
+    /// Whether agents got the archetype they will keep, none when the folder
+    /// cannot be loaded
+    pub fn is_settled(&self, asset_server: &AssetServer) -> bool {
+        self.folder.is_settled(asset_server)
+    }
+
+    pub fn get(&self, archetype: &str) -> Option<&ArchetypeSchedule> {
+        self.schedules.get(archetype)
+    }
+}
+
+/// Routine of an NPC. Places are kept as tiles since rooms are respawned when
+/// the level changes.
+#[derive(Component, Debug)]
+pub struct Schedule {
+    pub archetype: String,
+    pub home: Option<(i32, i32)>,
+    pub work: Option<(i32, i32)>,
+}
+
+/// Schedule on hold while the NPC does something else. The NPC heads back to
+/// its current block once removed.
+#[derive(Component)]
+pub struct SchedulePaused;
+
+#[derive(Component, Default, Debug)]
+pub struct CurrentActivity {
+    pub name: Option<String>,
+    block: Option<usize>,
+    index: usize,
+    /// Game minute the activity ends, `None` until the NPC gets there
+    until: Option<f64>,
+}
+
+fn load_schedules(mut library: ResMut<ScheduleLibrary>, asset_server: Res<AssetServer>) {
+    library.folder = NamedFolder::load(&asset_server, SCHEDULES_DIR);
+}
+
+/// Rebuilds the library when the schedules folder loads or one of its files
+/// changes. NPCs restart their current block from the new schedule.
+fn update_schedule_library(
+    mut folder_events: MessageReader<AssetEvent<LoadedFolder>>,
+    mut schedule_events: MessageReader<AssetEvent<ArchetypeSchedule>>,
+    mut library: ResMut<ScheduleLibrary>,
+    folders: Res<Assets<LoadedFolder>>,
+    schedules: Res<Assets<ArchetypeSchedule>>,
+    mut activities_q: Query<&mut CurrentActivity>,
+) {
+    let folder_changed = library.folder.is_changed(&mut folder_events);
+    let schedule_changed = schedule_events.read().any(|event| {
+        matches!(
+            event,
+            AssetEvent::Modified { .. } | AssetEvent::Removed { .. }
+        )
+    });
+
+    if !folder_changed && !schedule_changed {
+        return;
+    }
+
+    let Some(handles) = library
+        .folder
+        .read::<ArchetypeSchedule>(&folders, SCHEDULE_EXTENSION)
+    else {
+        return;
+    };
+
+    let loaded: HashMap<String, ArchetypeSchedule> = handles
+        .into_iter()
+        .filter_map(|(name, handle)| Some((name, schedules.get(&handle)?.clone())))
+        .collect();
+
+    info!("schedules: {:?}", loaded.keys().collect::<Vec<_>>());
+    library.schedules = loaded;
+
+    for mut current in &mut activities_q {
+        current.block = None;
+    }
+}
+
+/// Agents get a random archetype once the schedules are loaded, those without
+/// one keep wandering
+pub fn assign_archetypes(
+    query: Query<Entity, (With<Agent>, Without<Schedule>)>,
+    library: Res<ScheduleLibrary>,
+    mut commands: Commands,
+) {
+    let mut rng = rand::thread_rng();
+
+    for entity in &query {
+        let Some(archetype) = library.schedules.keys().choose(&mut rng) else {
+            return;
+        };
+
+        commands.entity(entity).insert((
+            Schedule {
+                archetype: archetype.clone(),
+                home: None,
+                work: None,
+            },
+            CurrentActivity::default(),
+        ));
+    }
+}
+
+fn on_resume_schedule(remove: On<Remove, SchedulePaused>, mut query: Query<&mut CurrentActivity>) {
+    if let Ok(mut current) = query.get_mut(remove.entity) {
+        current.block = None;
+    }
+}
+
+/// Finds free destination tiles for schedule places
+#[derive(SystemParam)]
+pub struct Places<'w, 's> {
+    pub rooms_q: Query<'w, 's, &'static Room>,
+    room_idx: Res<'w, RoomIndex>,
+    spatial_idx: Res<'w, SpatialIndex>,
+    free_tiles_q: Query<'w, 's, (), (With<Tile>, Without<Occupied>)>,
+}
+
+impl Places<'_, '_> {
+    fn is_free(&self, x: i32, y: i32) -> bool {
+        self.spatial_idx.get(x, y).is_some_and(|tile_data| {
+            tile_data.is_valid_destination() && self.free_tiles_q.get(tile_data.entity).is_ok()
+        })
+    }
+
+    fn random_tile_in(&self, room: &Room) -> Option<(i32, i32)> {
+        room.tiles
+            .iter()
+            .filter(|(x, y)| self.is_free(*x, *y))
+            .choose(&mut rand::thread_rng())
+            .copied()
+    }
+
+    fn random_room_tile(&self, name: Option<&str>) -> Option<(i32, i32)> {
+        let rooms: Vec<&Room> = self
+            .rooms_q
+            .iter()
+            .filter(|room| name.is_none_or(|name| room.name.as_deref() == Some(name)))
+            .collect();
+
+        if let Some(name) = name
+            && rooms.is_empty()
+        {
+            warn!("no room named {:?}, check the levels' `Rooms` field", name);
+        }
+
+        rooms
+            .choose(&mut rand::thread_rng())
+            .and_then(|room| self.random_tile_in(room))
+    }
+
+    /// Random free tile of the room holding `tile`
+    fn around(&self, tile: Option<(i32, i32)>) -> Option<(i32, i32)> {
+        let (x, y) = tile?;
+        let room = self.rooms_q.get(self.room_idx.room_at(x, y)?).ok()?;
+        self.random_tile_in(room)
+    }
+
+    /// Free tile closest to `center` within `radius`, `center` excluded
+    pub fn free_tile_near(&self, center: (i32, i32), radius: i32) -> Option<(i32, i32)> {
+        let (cx, cy) = center;
+
+        (cy - radius..=cy + radius)
+            .flat_map(|y| (cx - radius..=cx + radius).map(move |x| (x, y)))
+            .filter(|&tile| tile != center && self.is_free(tile.0, tile.1))
+            .min_by_key(|(x, y)| (x - cx).pow(2) + (y - cy).pow(2))
+    }
+
+    pub fn anywhere(&self) -> Option<(i32, i32)> {
+        self.spatial_idx
+            .iter()
+            .filter(|((x, y), _)| self.is_free(*x, *y))
+            .map(|(tile, _)| tile)
+            .choose(&mut rand::thread_rng())
+    }
+
+    fn destination(&self, place: &Place, schedule: &Schedule) -> Option<GridPosition> {
+        let tile = match place {
+            Place::Home => self.around(schedule.home),
+            Place::Work => self.around(schedule.work),
+            Place::Room(name) => self.random_room_tile(Some(name)),
+            Place::Anywhere => None,
+        };
+
+        // Unknown places fall back to wandering
+        tile.or_else(|| self.anywhere())
+            .map(|(x, y)| GridPosition { x, y })
+    }
+}
+
+/// Gives NPCs a home and a workplace, again when their room no longer exists
+fn assign_schedule_places(
+    mut query: Query<&mut Schedule>,
+    library: Res<ScheduleLibrary>,
+    places: Places,
+) {
+    if places.rooms_q.is_empty() {
+        return;
+    }
+
+    let is_room = |tile: Option<(i32, i32)>| {
+        tile.is_some_and(|(x, y)| places.room_idx.room_at(x, y).is_some())
+    };
+
+    for mut schedule in &mut query {
+        if !is_room(schedule.home) {
+            schedule.home = places.random_room_tile(None);
+        }
+
+        if !is_room(schedule.work) {
+            let work_room = library
+                .get(&schedule.archetype)
+                .and_then(|archetype| archetype.work.as_deref());
+
+            schedule.work = places
+                .random_room_tile(work_room)
+                .or_else(|| places.random_room_tile(None));
+        }
+    }
+}
+
+type ScheduledAgent = (
+    Entity,
+    &'static Schedule,
+    &'static mut CurrentActivity,
+    &'static mut AnimationController,
+    Has<Walking>,
+);
+
+/// Sends NPCs to the place of the current block and runs its activities in turn
+fn update_schedules(
+    mut query: Query<ScheduledAgent, Without<SchedulePaused>>,
+    clock: Res<GameClock>,
+    library: Res<ScheduleLibrary>,
+    places: Places,
+    mut commands: Commands,
+) {
+    let now = clock.total_minutes();
+
+    for (entity, schedule, mut current, mut controller, walking) in &mut query {
+        let Some(archetype) = library.get(&schedule.archetype) else {
+            continue;
+        };
+
+        let Some(block_idx) = archetype.block_at(clock.hour()) else {
+            continue;
+        };
+        let block = &archetype.blocks[block_idx];
+
+        if current.block != Some(block_idx) {
+            current.block = Some(block_idx);
+            current.index = 0;
+            current.until = None;
+            current.name = block
+                .activities
+                .first()
+                .map(|activity| activity.name.clone());
+
+            if let Some(destination) = places.destination(&block.place, schedule) {
+                debug!(
+                    "{:?} heads to {:?} ({:?})",
+                    entity, block.place, destination
+                );
+                commands.entity(entity).insert(Walking { destination });
+            }
+            continue;
+        }
+
+        // Activities start once the NPC gets there
+        if walking {
+            continue;
+        }
+
+        let Some(activity) = block.activities.get(current.index) else {
+            continue;
+        };
+
+        match current.until {
+            None => {
+                current.until = Some(now + activity.minutes);
+                controller.play(activity.clip.as_deref().unwrap_or("idle"));
+            }
+            Some(until) if now >= until => {
+                current.index = (current.index + 1) % block.activities.len();
+                current.until = None;
+
+                let next = &block.activities[current.index];
+                current.name = Some(next.name.clone());
+
+                if next.roam
+                    && let Some(destination) = places.destination(&block.place, schedule)
+                {
+                    commands.entity(entity).insert(Walking { destination });
+                }
+            }
+            Some(_) => {}
+        }
+    }
+}
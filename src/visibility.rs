@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+
+use bevy::{color::palettes::css::GOLD, prelude::*};
+
+use crate::{
+    agent::SelectedAgent,
+    constants::*,
+    world::{components::*, grid::Grid, spatial_idx::*},
+};
+
+pub struct FieldOfViewPlugin;
+
+impl Plugin for FieldOfViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (update_visible_tiles, draw_selected_agent_fov));
+    }
+}
+
+/// Tiles an agent can currently see, recomputed when it changes tile
+#[derive(Component, Default, Debug)]
+pub struct VisibleTiles {
+    pub origin: Option<GridPosition>,
+    pub tiles: HashSet<(i32, i32)>,
+}
+
+// #########################
+// LINE OF SIGHT
+// #########################
+
+/// Bresenham line from `from` to `to`, both end points included
+pub struct BresenhamLine {
+    x: i32,
+    y: i32,
+    to: (i32, i32),
+    dx: i32,
+    dy: i32,
+    sx: i32,
+    sy: i32,
+    err: i32,
+    done: bool,
+}
+
+impl BresenhamLine {
+    pub fn new(from: &GridPosition, to: &GridPosition) -> Self {
+        let (dx, dy) = ((to.x - from.x).abs(), -(to.y - from.y).abs());
+
+        Self {
+            x: from.x,
+            y: from.y,
+            to: (to.x, to.y),
+            dx,
+            dy,
+            sx: (to.x - from.x).signum(),
+            sy: (to.y - from.y).signum(),
+            err: dx + dy,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for BresenhamLine {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let current = (self.x, self.y);
+
+        if current == self.to {
+            self.done = true;
+            return Some(current);
+        }
+
+        let e2 = 2 * self.err;
+        if e2 >= self.dy {
+            self.err += self.dy;
+            self.x += self.sx;
+        }
+        if e2 <= self.dx {
+            self.err += self.dx;
+            self.y += self.sy;
+        }
+
+        Some(current)
+    }
+}
+
+/// Whether `to` can be seen from `from`: no wall on the line between them
+/// (end points excluded)
+pub fn line_of_sight(spatial_idx: &SpatialIndex, from: &GridPosition, to: &GridPosition) -> bool {
+    BresenhamLine::new(from, to)
+        .filter(|pos| *pos != (from.x, from.y) && *pos != (to.x, to.y))
+        .all(|(x, y)| !blocks_sight(spatial_idx, x, y))
+}
+
+/// Walls block sight, and so does anything outside the loaded chunks
+fn blocks_sight(spatial_idx: &SpatialIndex, x: i32, y: i32) -> bool {
+    match spatial_idx.get(x, y) {
+        Some(tile_data) => tile_data.is_wall(),
+        None => true,
+    }
+}
+
+// #########################
+// FIELD OF VIEW
+// #########################
+
+// Octant transforms (xx, xy, yx, yy) for recursive shadowcasting
+const OCTANTS: [(i32, i32, i32, i32); 8] = [
+    (1, 0, 0, 1),
+    (0, 1, 1, 0),
+    (0, -1, 1, 0),
+    (-1, 0, 0, 1),
+    (-1, 0, 0, -1),
+    (0, -1, -1, 0),
+    (0, 1, -1, 0),
+    (1, 0, 0, -1),
+];
+
+/// Recursive shadowcasting FOV around `origin`. Walls are visible but block
+/// everything behind them.
+pub fn compute_fov(
+    spatial_idx: &SpatialIndex,
+    origin: &GridPosition,
+    radius: i32,
+) -> HashSet<(i32, i32)> {
+    let mut visible = HashSet::new();
+    visible.insert((origin.x, origin.y));
+
+    for octant in OCTANTS {
+        cast_light(
+            spatial_idx,
+            origin,
+            radius,
+            1,
+            1.0,
+            0.0,
+            octant,
+            &mut visible,
+        );
+    }
+
+    visible
+}
+
+#[allow(clippy::too_many_arguments)]
+fn cast_light(
+    spatial_idx: &SpatialIndex,
+    origin: &GridPosition,
+    radius: i32,
+    row: i32,
+    mut start_slope: f32,
+    end_slope: f32,
+    (xx, xy, yx, yy): (i32, i32, i32, i32),
+    visible: &mut HashSet<(i32, i32)>,
+) {
+    if start_slope < end_slope {
+        return;
+    }
+
+    let mut next_start_slope = start_slope;
+
+    for distance in row..=radius {
+        let mut blocked = false;
+        let dy = -distance;
+
+        for dx in -distance..=0 {
+            let left_slope = (dx as f32 - 0.5) / (dy as f32 + 0.5);
+            let right_slope = (dx as f32 + 0.5) / (dy as f32 - 0.5);
+
+            if start_slope < right_slope {
+                continue;
+            }
+            if end_slope > left_slope {
+                break;
+            }
+
+            let x = origin.x + dx * xx + dy * xy;
+            let y = origin.y + dx * yx + dy * yy;
+
+            if dx * dx + dy * dy <= radius * radius {
+                visible.insert((x, y));
+            }
+
+            let opaque = blocks_sight(spatial_idx, x, y);
+
+            if blocked {
+                if opaque {
+                    next_start_slope = right_slope;
+                } else {
+                    blocked = false;
+                    start_slope = next_start_slope;
+                }
+            } else if opaque && distance < radius {
+                blocked = true;
+                cast_light(
+                    spatial_idx,
+                    origin,
+                    radius,
+                    distance + 1,
+                    start_slope,
+                    left_slope,
+                    (xx, xy, yx, yy),
+                    visible,
+                );
+                next_start_slope = right_slope;
+            }
+        }
+
+        if blocked {
+            break;
+        }
+    }
+}
+
+fn update_visible_tiles(
+    mut query: Query<(&GridPosition, &mut VisibleTiles), Changed<GridPosition>>,
+    spatial_idx: Res<SpatialIndex>,
+) {
+    for (position, mut visible_tiles) in &mut query {
+        if visible_tiles.origin.as_ref() == Some(position) {
+            continue;
+        }
+
+        visible_tiles.tiles = compute_fov(&spatial_idx, position, SIGHT_RADIUS);
+        visible_tiles.origin = Some(position.clone());
+    }
+}
+
+fn draw_selected_agent_fov(
+    selected: Res<SelectedAgent>,
+    query: Query<&VisibleTiles>,
+    mut gizmos: Gizmos,
+) {
+    let Some(visible_tiles) = selected.0.and_then(|entity| query.get(entity).ok()) else {
+        return;
+    };
+
+    let shade = Color::from(GOLD).with_alpha(0.35);
+
+    for &(x, y) in &visible_tiles.tiles {
+        let pos = Grid::grid_to_world(x, y).truncate();
+
+        gizmos.rect_2d(pos, Vec2::splat(TILE_SIZE), shade);
+        gizmos.rect_2d(pos, Vec2::splat(TILE_SIZE / 2.0), shade);
+    }
+}
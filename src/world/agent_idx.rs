@@ -0,0 +1,96 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+
+use crate::{agent::Agent, constants::AGENT_CELL_SIZE, world::components::*};
+
+/// Spatial hash of agents by `GridPosition`, bucketed in `AGENT_CELL_SIZE` cells.
+#[derive(Resource, Default, Debug)]
+pub struct AgentIndex {
+    cells: HashMap<IVec2, Vec<Entity>>,
+    positions: HashMap<Entity, GridPosition>,
+}
+
+impl AgentIndex {
+    fn cell_of(x: i32, y: i32) -> IVec2 {
+        IVec2::new(x.div_euclid(AGENT_CELL_SIZE), y.div_euclid(AGENT_CELL_SIZE))
+    }
+
+    pub fn insert(&mut self, entity: Entity, position: GridPosition) {
+        self.remove(entity);
+
+        self.cells
+            .entry(AgentIndex::cell_of(position.x, position.y))
+            .or_default()
+            .push(entity);
+        self.positions.insert(entity, position);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some(old) = self.positions.remove(&entity) {
+            let cell = AgentIndex::cell_of(old.x, old.y);
+            if let Some(entities) = self.cells.get_mut(&cell) {
+                entities.retain(|e| *e != entity);
+                if entities.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Agents inside the inclusive tile rect `min..=max`
+    pub fn agents_in_rect(
+        &self,
+        min: IVec2,
+        max: IVec2,
+    ) -> impl Iterator<Item = (Entity, &GridPosition)> {
+        let min_cell = AgentIndex::cell_of(min.x, min.y);
+        let max_cell = AgentIndex::cell_of(max.x, max.y);
+
+        (min_cell.y..=max_cell.y)
+            .flat_map(move |y| (min_cell.x..=max_cell.x).map(move |x| IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter_map(|entity| self.positions.get(entity).map(|pos| (*entity, pos)))
+            .filter(move |(_, pos)| {
+                pos.x >= min.x && pos.x <= max.x && pos.y >= min.y && pos.y <= max.y
+            })
+    }
+
+    /// Agents within `radius` tiles (euclidean) of `center`
+    pub fn agents_in_radius(
+        &self,
+        center: &GridPosition,
+        radius: i32,
+    ) -> impl Iterator<Item = (Entity, &GridPosition)> {
+        let (cx, cy) = (center.x, center.y);
+        let min = IVec2::new(cx - radius, cy - radius);
+        let max = IVec2::new(cx + radius, cy + radius);
+
+        self.agents_in_rect(min, max)
+            .filter(move |(_, pos)| (pos.x - cx).pow(2) + (pos.y - cy).pow(2) <= radius.pow(2))
+    }
+
+    /// Closest agent to `center` within `max_radius` tiles, ignoring `exclude`
+    pub fn nearest_agent(
+        &self,
+        center: &GridPosition,
+        max_radius: i32,
+        exclude: Option<Entity>,
+    ) -> Option<(Entity, &GridPosition)> {
+        self.agents_in_radius(center, max_radius)
+            .filter(|(entity, _)| Some(*entity) != exclude)
+            .min_by_key(|(_, pos)| (pos.x - center.x).pow(2) + (pos.y - center.y).pow(2))
+    }
+}
+
+pub fn update_agent_index(
+    query: Query<(Entity, &GridPosition), (With<Agent>, Changed<GridPosition>)>,
+    mut index: ResMut<AgentIndex>,
+) {
+    for (entity, position) in &query {
+        index.insert(entity, position.clone());
+    }
+}
+
+pub fn on_remove_agent(remove: On<Remove, Agent>, mut index: ResMut<AgentIndex>) {
+    index.remove(remove.entity);
+}
@@ -32,6 +32,18 @@ pub struct GridPosition {
     pub y: i32,
 }
 
+impl GridPosition {
+    /// Euclidean distance, in tiles
+    pub fn distance(&self, other: &GridPosition) -> f32 {
+        (((self.x - other.x).pow(2) + (self.y - other.y).pow(2)) as f32).sqrt()
+    }
+
+    /// Number of 8-way steps between the two positions
+    pub fn chebyshev(&self, other: &GridPosition) -> i32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs())
+    }
+}
+
 // #########################
 // OCCUPIED
 // #########################
@@ -1,13 +1,17 @@
 use bevy::prelude::*;
 
-use rand::Rng;
+use rand::seq::IteratorRandom;
 
-use crate::world::components::*;
+use crate::world::{components::*, spatial_idx::SpatialIndex};
 
-use crate::constants::{GRID_HEIGHT, GRID_WIDTH, TILE_SIZE};
+use crate::constants::TILE_SIZE;
 
 pub struct Grid;
 
+/// Grid tile under the mouse cursor, updated every frame
+#[derive(Resource, Default, Debug, PartialEq)]
+pub struct CursorTile(pub Option<GridPosition>);
+
 impl Grid {
     /// Convert grid coordinates → world coordinates (Vec3)
     pub fn grid_to_world(x: i32, y: i32) -> Vec3 {
@@ -18,11 +22,53 @@ impl Grid {
         )
     }
 
-    pub fn get_random_position() -> GridPosition {
-        let mut rnd = rand::thread_rng();
+    /// Convert world coordinates → grid coordinates
+    pub fn world_to_grid(pos: Vec2) -> GridPosition {
         GridPosition {
-            x: rnd.gen_range(0..GRID_WIDTH),
-            y: rnd.gen_range(0..GRID_HEIGHT),
+            x: (pos.x / TILE_SIZE).floor() as i32,
+            y: (pos.y / TILE_SIZE).floor() as i32,
         }
     }
+
+    /// Inclusive tile rect currently seen by a 2d camera
+    pub fn camera_view_rect(
+        camera: &Camera,
+        camera_transform: &GlobalTransform,
+    ) -> Option<(IVec2, IVec2)> {
+        let viewport_size = camera.logical_viewport_size()?;
+
+        let top_left = camera
+            .viewport_to_world_2d(camera_transform, Vec2::ZERO)
+            .ok()?;
+        let bottom_right = camera
+            .viewport_to_world_2d(camera_transform, viewport_size)
+            .ok()?;
+
+        let min = Grid::world_to_grid(top_left.min(bottom_right));
+        let max = Grid::world_to_grid(top_left.max(bottom_right));
+
+        Some((IVec2::new(min.x, min.y), IVec2::new(max.x, max.y)))
+    }
+
+    /// Grid coordinates under the mouse cursor, if it is inside the window
+    pub fn cursor_to_grid(
+        window: &Window,
+        camera: &Camera,
+        camera_transform: &GlobalTransform,
+    ) -> Option<GridPosition> {
+        let cursor_pos = window.cursor_position()?;
+
+        camera
+            .viewport_to_world_2d(camera_transform, cursor_pos)
+            .ok()
+            .map(Grid::world_to_grid)
+    }
+
+    /// Random position among the tiles of every loaded level
+    pub fn get_random_position(spatial_idx: &SpatialIndex) -> Option<GridPosition> {
+        spatial_idx
+            .iter()
+            .map(|((x, y), _)| GridPosition { x, y })
+            .choose(&mut rand::thread_rng())
+    }
 }
@@ -1,6 +1,10 @@
+pub mod agent_idx;
 pub mod components;
 pub mod plugin;
+pub mod rooms;
 pub mod spatial_idx;
 pub mod grid;
 mod systems;
 
+
+pub use systems::LevelOrigins;
@@ -1,9 +1,13 @@
 use bevy::prelude::*;
 
 use crate::world::{
+    agent_idx::{AgentIndex, on_remove_agent, update_agent_index},
+    grid::CursorTile,
+    rooms::{RoomIndex, RoomNames, collect_room_names, rebuild_rooms, update_current_room},
     spatial_idx::SpatialIndex,
     systems::{
-        on_add_tile, on_add_tile_enum_tags, on_agent_entered_tile, on_agent_left_tile, spawn_grid,
+        LevelOrigins, on_add_tile, on_add_tile_enum_tags, on_agent_entered_tile,
+        on_agent_left_tile, on_remove_level, on_remove_tile, spawn_level_grid, update_cursor_tile,
     },
 };
 
@@ -12,10 +16,23 @@ pub struct WorldPlugin;
 impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<SpatialIndex>()
+            .init_resource::<AgentIndex>()
+            .init_resource::<RoomIndex>()
+            .init_resource::<RoomNames>()
+            .init_resource::<CursorTile>()
+            .init_resource::<LevelOrigins>()
             .add_observer(on_add_tile_enum_tags)
             .add_observer(on_add_tile)
+            .add_observer(on_remove_tile)
             .add_observer(on_agent_left_tile)
             .add_observer(on_agent_entered_tile)
-            .add_systems(PreStartup, spawn_grid);
+            .add_observer(on_remove_agent)
+            .add_observer(spawn_level_grid)
+            .add_observer(on_remove_level)
+            .add_systems(PreUpdate, (update_agent_index, update_cursor_tile))
+            .add_systems(
+                Update,
+                (collect_room_names, rebuild_rooms, update_current_room).chain(),
+            );
     }
 }
@@ -0,0 +1,289 @@
+use std::collections::{HashSet, VecDeque};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_ecs_ldtk::{ldtk::FieldValue, prelude::*};
+
+use crate::world::{
+    components::*,
+    spatial_idx::*,
+    systems::{level_bounds, raw_level},
+};
+
+/// LDtk level field listing room names as `"x,y=Name"` entries, where `x,y` is
+/// any grid tile inside the room, relative to the level
+const ROOM_NAMES_FIELD: &str = "Rooms";
+
+/// Connected area of `INSIDE` tiles, bounded by walls and doors
+#[derive(Component, Debug)]
+pub struct Room {
+    pub min: IVec2,
+    pub max: IVec2,
+    pub tiles: Vec<(i32, i32)>,
+    pub doors: Vec<GridPosition>,
+    pub area: usize,
+    pub name: Option<String>,
+}
+
+/// Connected area of building tiles (inside, doors, walls and roofs). Its rooms
+/// are spawned as children.
+#[derive(Component, Debug)]
+pub struct Building {
+    pub tiles: Vec<(i32, i32)>,
+}
+
+/// Room the entity is standing in, `None` outside or on a door
+#[derive(Component, Default, Debug, PartialEq, Eq)]
+pub struct CurrentRoom(pub Option<Entity>);
+
+#[derive(Resource, Default, Debug)]
+pub struct RoomIndex {
+    by_tile: HashMap<(i32, i32), Entity>,
+    building_by_tile: HashMap<(i32, i32), Entity>,
+}
+
+impl RoomIndex {
+    pub fn room_at(&self, x: i32, y: i32) -> Option<Entity> {
+        self.by_tile.get(&(x, y)).copied()
+    }
+
+    pub fn building_at(&self, x: i32, y: i32) -> Option<Entity> {
+        self.building_by_tile.get(&(x, y)).copied()
+    }
+}
+
+/// Room names read from the levels' `Rooms` field
+#[derive(Resource, Default, Debug)]
+pub struct RoomNames(pub Vec<(GridPosition, String)>);
+
+fn is_room_tile(tile_data: &TileData) -> bool {
+    tile_data.flags.contains(TileFlags::INSIDE)
+        && !tile_data
+            .flags
+            .intersects(TileFlags::WALL | TileFlags::DOOR)
+}
+
+/// 4-neighbour flood fill from `start` over the tiles accepted by `is_member`.
+/// Rejected neighbours are handed to `on_border`.
+fn flood_fill(
+    spatial_idx: &SpatialIndex,
+    start: (i32, i32),
+    visited: &mut HashSet<(i32, i32)>,
+    is_member: impl Fn(&TileData) -> bool,
+    mut on_border: impl FnMut((i32, i32), &TileData),
+) -> Vec<(i32, i32)> {
+    let mut tiles = vec![];
+
+    let mut open = VecDeque::from([start]);
+    visited.insert(start);
+
+    while let Some((x, y)) = open.pop_front() {
+        tiles.push((x, y));
+
+        for neighbor in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+            let Some(neighbor_data) = spatial_idx.get(neighbor.0, neighbor.1) else {
+                continue;
+            };
+
+            if !is_member(neighbor_data) {
+                on_border(neighbor, neighbor_data);
+            } else if visited.insert(neighbor) {
+                open.push_back(neighbor);
+            }
+        }
+    }
+
+    tiles
+}
+
+/// Flood fills the inside tiles of the index into rooms
+pub fn segment_rooms(spatial_idx: &SpatialIndex) -> Vec<Room> {
+    let mut visited: HashSet<(i32, i32)> = HashSet::new();
+    let mut rooms = vec![];
+
+    for (start, tile_data) in spatial_idx.iter() {
+        if visited.contains(&start) || !is_room_tile(tile_data) {
+            continue;
+        }
+
+        let mut doors: HashSet<(i32, i32)> = HashSet::new();
+        let tiles = flood_fill(
+            spatial_idx,
+            start,
+            &mut visited,
+            is_room_tile,
+            |pos, border_data| {
+                if border_data.flags.contains(TileFlags::DOOR) {
+                    doors.insert(pos);
+                }
+            },
+        );
+
+        let min = tiles
+            .iter()
+            .fold(IVec2::MAX, |min, &(x, y)| min.min(IVec2::new(x, y)));
+        let max = tiles
+            .iter()
+            .fold(IVec2::MIN, |max, &(x, y)| max.max(IVec2::new(x, y)));
+
+        rooms.push(Room {
+            min,
+            max,
+            area: tiles.len(),
+            tiles,
+            doors: doors
+                .into_iter()
+                .map(|(x, y)| GridPosition { x, y })
+                .collect(),
+            name: None,
+        });
+    }
+
+    rooms
+}
+
+/// Flood fills the building tiles of the index into buildings
+pub fn segment_buildings(spatial_idx: &SpatialIndex) -> Vec<Building> {
+    let mut visited: HashSet<(i32, i32)> = HashSet::new();
+    let mut buildings = vec![];
+
+    for (start, tile_data) in spatial_idx.iter() {
+        if visited.contains(&start) || !tile_data.is_building() {
+            continue;
+        }
+
+        let tiles = flood_fill(
+            spatial_idx,
+            start,
+            &mut visited,
+            TileData::is_building,
+            |_, _| {},
+        );
+
+        buildings.push(Building { tiles });
+    }
+
+    buildings
+}
+
+pub fn collect_room_names(
+    levels: Query<&LevelIid, Added<LevelIid>>,
+    projects: Query<&LdtkProjectHandle>,
+    project_assets: Res<Assets<LdtkProject>>,
+    mut room_names: ResMut<RoomNames>,
+) {
+    for level_iid in &levels {
+        let Some(level) = raw_level(level_iid, &projects, &project_assets) else {
+            continue;
+        };
+        let (origin, _) = level_bounds(level);
+
+        for field in &level.field_instances {
+            if field.identifier != ROOM_NAMES_FIELD {
+                continue;
+            }
+
+            if let FieldValue::Strings(entries) = &field.value {
+                for entry in entries.iter().flatten() {
+                    match parse_room_name(entry) {
+                        Some((pos, name)) => room_names.0.push((
+                            GridPosition {
+                                x: origin.x + pos.x,
+                                y: origin.y + pos.y,
+                            },
+                            name,
+                        )),
+                        None => warn!("invalid room name entry: {:?}", entry),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_room_name(entry: &str) -> Option<(GridPosition, String)> {
+    let (coords, name) = entry.split_once('=')?;
+    let (x, y) = coords.split_once(',')?;
+
+    Some((
+        GridPosition {
+            x: x.trim().parse().ok()?,
+            y: y.trim().parse().ok()?,
+        },
+        name.trim().to_string(),
+    ))
+}
+
+/// Rebuilds the `Building` and `Room` entities whenever the tile flags or room
+/// names change
+pub fn rebuild_rooms(
+    mut commands: Commands,
+    spatial_idx: Res<SpatialIndex>,
+    room_names: Res<RoomNames>,
+    mut room_idx: ResMut<RoomIndex>,
+    buildings_q: Query<Entity, With<Building>>,
+) {
+    if !spatial_idx.is_changed() && !room_names.is_changed() {
+        return;
+    }
+
+    // Rooms go away with their building
+    for entity in &buildings_q {
+        commands.entity(entity).despawn();
+    }
+    room_idx.by_tile.clear();
+    room_idx.building_by_tile.clear();
+
+    for building in segment_buildings(&spatial_idx) {
+        let building_entity = commands.spawn_empty().id();
+        for tile in &building.tiles {
+            room_idx.building_by_tile.insert(*tile, building_entity);
+        }
+
+        commands.entity(building_entity).insert(building);
+    }
+
+    for mut room in segment_rooms(&spatial_idx) {
+        room.name = room_names
+            .0
+            .iter()
+            .find(|(pos, _)| room.tiles.contains(&(pos.x, pos.y)))
+            .map(|(_, name)| name.clone());
+
+        debug!(
+            "room {:?}: {} tiles, {} doors",
+            room.name,
+            room.area,
+            room.doors.len()
+        );
+
+        let mut room_commands = commands.spawn_empty();
+        if let Some(name) = &room.name {
+            room_commands.insert(Name::new(name.clone()));
+        }
+
+        // Room tiles are inside tiles, so always part of a building
+        if let Some(building_entity) = room_idx.building_at(room.tiles[0].0, room.tiles[0].1) {
+            room_commands.insert(ChildOf(building_entity));
+        }
+
+        let room_entity = room_commands.id();
+        for tile in &room.tiles {
+            room_idx.by_tile.insert(*tile, room_entity);
+        }
+
+        room_commands.insert(room);
+    }
+}
+
+pub fn update_current_room(
+    mut query: Query<(Ref<GridPosition>, &mut CurrentRoom)>,
+    room_idx: Res<RoomIndex>,
+) {
+    for (position, mut current_room) in &mut query {
+        if !position.is_changed() && !room_idx.is_changed() {
+            continue;
+        }
+
+        current_room.set_if_neq(CurrentRoom(room_idx.room_at(position.x, position.y)));
+    }
+}
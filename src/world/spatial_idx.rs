@@ -1,12 +1,14 @@
 use bevy::{platform::collections::HashMap, prelude::*};
 
-use crate::world::components::*;
+use crate::{constants::CHUNK_SIZE, world::components::*};
 
 #[derive(Clone, Copy, Debug)]
 pub struct TileData {
     pub entity: Entity,
     pub flags: TileFlags,
     pub tilemap_entity: Option<Entity>,
+    /// LDtk tile entity (the one living in `tilemap_entity`) that tagged this position
+    pub tile_entity: Option<Entity>,
 }
 
 impl TileData {
@@ -90,25 +92,170 @@ impl TileData {
     }
 }
 
+// #########################
+// CHUNK
+// #########################
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ChunkCoord {
+    pub fn from_tile(x: i32, y: i32) -> Self {
+        Self {
+            x: x.div_euclid(CHUNK_SIZE),
+            y: y.div_euclid(CHUNK_SIZE),
+        }
+    }
+
+    /// Tile coordinates of the bottom-left corner of this chunk
+    pub fn origin(&self) -> (i32, i32) {
+        (self.x * CHUNK_SIZE, self.y * CHUNK_SIZE)
+    }
+}
+
+/// Fixed `CHUNK_SIZE`×`CHUNK_SIZE` block of tiles.
+#[derive(Debug)]
+pub struct Chunk {
+    coord: ChunkCoord,
+    tiles: Vec<Option<TileData>>,
+    len: usize,
+}
+
+impl Chunk {
+    fn new(coord: ChunkCoord) -> Self {
+        Self {
+            coord,
+            tiles: vec![None; (CHUNK_SIZE * CHUNK_SIZE) as usize],
+            len: 0,
+        }
+    }
+
+    fn local_idx(x: i32, y: i32) -> usize {
+        (y.rem_euclid(CHUNK_SIZE) * CHUNK_SIZE + x.rem_euclid(CHUNK_SIZE)) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&TileData> {
+        self.tiles[Chunk::local_idx(x, y)].as_ref()
+    }
+
+    pub fn get_mut(&mut self, x: i32, y: i32) -> Option<&mut TileData> {
+        self.tiles[Chunk::local_idx(x, y)].as_mut()
+    }
+
+    fn insert(&mut self, x: i32, y: i32, data: TileData) {
+        let slot = &mut self.tiles[Chunk::local_idx(x, y)];
+        if slot.is_none() {
+            self.len += 1;
+        }
+        *slot = Some(data);
+    }
+
+    fn remove(&mut self, x: i32, y: i32) -> Option<TileData> {
+        let removed = self.tiles[Chunk::local_idx(x, y)].take();
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Iterate the loaded tiles of this chunk with their world tile coordinates
+    pub fn iter(&self) -> impl Iterator<Item = ((i32, i32), &TileData)> {
+        let (origin_x, origin_y) = self.coord.origin();
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter_map(move |(idx, data)| {
+                data.as_ref().map(|data| {
+                    let idx = idx as i32;
+                    (
+                        (origin_x + idx % CHUNK_SIZE, origin_y + idx / CHUNK_SIZE),
+                        data,
+                    )
+                })
+            })
+    }
+}
+
+// #########################
+// SPATIAL INDEX
+// #########################
 #[derive(Resource, Default, Debug)]
 pub struct SpatialIndex {
-    pub map: HashMap<(i32, i32), TileData>,
+    chunks: HashMap<ChunkCoord, Chunk>,
 }
 
 impl SpatialIndex {
-    pub fn get_entity_data(&self, x: i32, y: i32) -> Option<TileData> {
-        // println!("get_entity: {} {}", x, y);
-        match self.map.get(&(x, y)) {
-            Some(data) => Some(*data),
-            None => None,
+    pub fn get_entity(&self, x: i32, y: i32) -> Option<Entity> {
+        self.get(x, y).map(|data| data.entity)
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&TileData> {
+        self.chunks
+            .get(&ChunkCoord::from_tile(x, y))
+            .and_then(|chunk| chunk.get(x, y))
+    }
+
+    /// Returns the tile at `(x, y)`, loading its chunk and inserting `default()` if missing
+    pub fn get_or_insert_with(
+        &mut self,
+        x: i32,
+        y: i32,
+        default: impl FnOnce() -> TileData,
+    ) -> &mut TileData {
+        let coord = ChunkCoord::from_tile(x, y);
+        let chunk = self
+            .chunks
+            .entry(coord)
+            .or_insert_with(|| Chunk::new(coord));
+
+        if chunk.get(x, y).is_none() {
+            chunk.insert(x, y, default());
         }
+
+        chunk.get_mut(x, y).expect("tile was just inserted")
     }
 
-    pub fn get_entity(&self, x: i32, y: i32) -> Option<Entity> {
-        // println!("get_entity: {} {}", x, y);
-        match self.map.get(&(x, y)) {
-            Some(data) => Some(data.entity),
-            None => None,
+    /// Removes the tile at `(x, y)`, unloading its chunk once it is empty
+    pub fn remove(&mut self, x: i32, y: i32) -> Option<TileData> {
+        let coord = ChunkCoord::from_tile(x, y);
+        let chunk = self.chunks.get_mut(&coord)?;
+        let removed = chunk.remove(x, y);
+
+        if chunk.is_empty() {
+            self.chunks.remove(&coord);
         }
+
+        removed
+    }
+
+    /// Loaded chunks overlapping the inclusive tile rect `min..=max`
+    pub fn chunks_in_rect(&self, min: IVec2, max: IVec2) -> impl Iterator<Item = &Chunk> {
+        let min_chunk = ChunkCoord::from_tile(min.x, min.y);
+        let max_chunk = ChunkCoord::from_tile(max.x, max.y);
+
+        (min_chunk.y..=max_chunk.y)
+            .flat_map(move |y| (min_chunk.x..=max_chunk.x).map(move |x| ChunkCoord { x, y }))
+            .filter_map(|coord| self.chunks.get(&coord))
+    }
+
+    /// Loaded tiles inside the inclusive tile rect `min..=max`, visited chunk by chunk
+    pub fn iter_rect(
+        &self,
+        min: IVec2,
+        max: IVec2,
+    ) -> impl Iterator<Item = ((i32, i32), &TileData)> {
+        self.chunks_in_rect(min, max)
+            .flat_map(|chunk| chunk.iter())
+            .filter(move |((x, y), _)| *x >= min.x && *x <= max.x && *y >= min.y && *y <= max.y)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((i32, i32), &TileData)> {
+        self.chunks.values().flat_map(|chunk| chunk.iter())
     }
 }
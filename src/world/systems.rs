@@ -1,14 +1,29 @@
-use bevy::prelude::*;
+use bevy::{platform::collections::HashMap, prelude::*, window::PrimaryWindow};
 use bevy_ecs_tilemap::map::TilemapId;
 
-use bevy_ecs_ldtk::prelude::*;
+use bevy_ecs_ldtk::{ldtk, prelude::*};
 
 use crate::{
-    constants::{GRID_HEIGHT, GRID_WIDTH},
+    constants::{GRID_HEIGHT, TILE_SIZE},
     events::{AgentEnteredTile, AgentLeftTile},
-    world::{components::*, grid::Grid, spatial_idx::*},
+    world::{components::*, grid::*, spatial_idx::*},
 };
 
+pub fn update_cursor_tile(
+    window: Single<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut cursor_tile: ResMut<CursorTile>,
+) {
+    let tile = camera_query
+        .single()
+        .ok()
+        .and_then(|(camera, camera_transform)| {
+            Grid::cursor_to_grid(&window, camera, camera_transform)
+        });
+
+    cursor_tile.set_if_neq(CursorTile(tile));
+}
+
 pub fn on_agent_left_tile(event: On<AgentLeftTile>, mut commands: Commands) {
     commands.entity(event.entity).remove::<Occupied>();
 }
@@ -17,37 +32,142 @@ pub fn on_agent_entered_tile(event: On<AgentEnteredTile>, mut commands: Commands
     commands.entity(event.entity).insert(Occupied);
 }
 
-pub fn spawn_grid(mut commands: Commands) {
-    for y in 0..GRID_HEIGHT {
-        for x in 0..GRID_WIDTH {
+/// Grid tile of the bottom-left corner and size in tiles of each spawned level
+#[derive(Resource, Default, Debug)]
+pub struct LevelOrigins(pub HashMap<Entity, (IVec2, IVec2)>);
+
+impl LevelOrigins {
+    /// Inclusive range of grid rows covered by the spawned levels
+    pub fn rows(&self) -> Option<(i32, i32)> {
+        let min = self.0.values().map(|(origin, _)| origin.y).min()?;
+        let max = self
+            .0
+            .values()
+            .map(|(origin, size)| origin.y + size.y - 1)
+            .max()?;
+
+        Some((min, max))
+    }
+}
+
+/// Bottom-left grid tile and size in tiles of a level. LDtk world coordinates
+/// grow downwards, from the top of the grid rows `0..GRID_HEIGHT` the first
+/// level sits on.
+pub fn level_bounds(level: &ldtk::Level) -> (IVec2, IVec2) {
+    let tile = TILE_SIZE as i32;
+    let origin = IVec2::new(
+        level.world_x / tile,
+        GRID_HEIGHT - (level.world_y + level.px_hei) / tile,
+    );
+
+    (origin, IVec2::new(level.px_wid / tile, level.px_hei / tile))
+}
+
+pub fn raw_level<'a>(
+    level_iid: &LevelIid,
+    projects: &Query<&LdtkProjectHandle>,
+    project_assets: &'a Assets<LdtkProject>,
+) -> Option<&'a ldtk::Level> {
+    projects.iter().find_map(|project_handle| {
+        project_assets
+            .get(&project_handle.handle)?
+            .get_raw_level_by_iid(level_iid.get())
+    })
+}
+
+/// Spawns the navigation grid of a level as children of the level entity, so the
+/// grid (and the `SpatialIndex` chunks it loads) goes away with the level.
+pub fn spawn_level_grid(
+    add: On<Add, LevelIid>,
+    levels_q: Query<&LevelIid>,
+    projects: Query<&LdtkProjectHandle>,
+    project_assets: Res<Assets<LdtkProject>>,
+    mut level_origins: ResMut<LevelOrigins>,
+    mut commands: Commands,
+) {
+    let Some(level) = levels_q
+        .get(add.entity)
+        .ok()
+        .and_then(|level_iid| raw_level(level_iid, &projects, &project_assets))
+    else {
+        warn!(
+            "no grid for level {:?}, its project is not loaded",
+            add.entity
+        );
+        return;
+    };
+
+    let (origin, size) = level_bounds(level);
+    level_origins.0.insert(add.entity, (origin, size));
+
+    for y in 0..size.y {
+        for x in 0..size.x {
+            // Relative to the level, which LDtk places at its world position
             let pos = Grid::grid_to_world(x, y);
+            let (x, y) = (origin.x + x, origin.y + y);
 
             commands.spawn((
                 Transform::from_translation(pos),
                 Tile { x, y },
                 GridPosition { x, y },
+                ChildOf(add.entity),
             ));
         }
     }
 }
 
+pub fn on_remove_level(remove: On<Remove, LevelIid>, mut level_origins: ResMut<LevelOrigins>) {
+    level_origins.0.remove(&remove.entity);
+}
+
 pub fn on_add_tile(add: On<Add, Tile>, query: Query<&Tile>, mut index: ResMut<SpatialIndex>) {
     if let Ok(tile) = query.get(add.entity) {
-        index.map.entry((tile.x, tile.y)).or_insert(TileData {
-            entity: add.entity,
-            flags: TileFlags::TRAVERSABLE_TERRAIN,
-            tilemap_entity: None,
-        });
+        // Enum tags may have been indexed before the grid tile was spawned
+        index
+            .get_or_insert_with(tile.x, tile.y, || TileData {
+                entity: add.entity,
+                flags: TileFlags::TRAVERSABLE_TERRAIN,
+                tilemap_entity: None,
+                tile_entity: None,
+            })
+            .entity = add.entity;
+    }
+}
+
+pub fn on_remove_tile(
+    remove: On<Remove, Tile>,
+    query: Query<&Tile>,
+    mut index: ResMut<SpatialIndex>,
+) {
+    // Another level may have taken the position since
+    if let Ok(tile) = query.get(remove.entity)
+        && index.get_entity(tile.x, tile.y) == Some(remove.entity)
+    {
+        index.remove(tile.x, tile.y);
     }
 }
 
+/// Tags of LDtk tiles set the flags of the grid tile under them. Their
+/// `GridCoords` are relative to the level of their layer.
 pub fn on_add_tile_enum_tags(
     add: On<Add, TileEnumTags>,
     query_third_party_tile: Query<(&TileEnumTags, &GridCoords, &TilemapId)>,
+    layers_q: Query<&ChildOf>,
+    level_origins: Res<LevelOrigins>,
     mut index: ResMut<SpatialIndex>,
 ) {
     let (enum_tags, coords, tilemap_id) = query_third_party_tile.get(add.entity).unwrap();
 
+    let Some((origin, _)) = layers_q
+        .get(tilemap_id.0)
+        .ok()
+        .and_then(|layer_parent| level_origins.0.get(&layer_parent.parent()))
+    else {
+        warn!("tile {:?} is not in a spawned level", add.entity);
+        return;
+    };
+    let (x, y) = (origin.x + coords.x, origin.y + coords.y);
+
     let mut tile_flags = TileFlags::empty();
 
     if enum_tags.tags.iter().any(|t| t == "Wall") {
@@ -67,8 +187,13 @@ pub fn on_add_tile_enum_tags(
         return;
     };
 
-    if let Some(tile_data) = index.map.get_mut(&(coords.x, coords.y)) {
-        tile_data.flags |= tile_flags;
-        tile_data.tilemap_entity = Some(tilemap_id.0);
-    }
+    let tile_data = index.get_or_insert_with(x, y, || TileData {
+        entity: Entity::PLACEHOLDER,
+        flags: TileFlags::TRAVERSABLE_TERRAIN,
+        tilemap_entity: None,
+        tile_entity: None,
+    });
+    tile_data.flags |= tile_flags;
+    tile_data.tilemap_entity = Some(tilemap_id.0);
+    tile_data.tile_entity = Some(add.entity);
 }